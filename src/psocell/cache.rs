@@ -0,0 +1,155 @@
+use super::{source::ShaderSource, stages::Stages, ShaderWatchError};
+use gfx::*;
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// Shader type, ie `VertexShader<R>`, shader file, `None` for compile time shader bytes,
+/// & hash of the expanded code
+type CacheKey = (TypeId, Option<PathBuf>, u64);
+
+fn key<S: Any>(source: &ShaderSource) -> CacheKey {
+    (
+        TypeId::of::<S>(),
+        source.path().map(PathBuf::from),
+        source.code_hash(),
+    )
+}
+
+/// Compiled shaders shared by cells, see `WatcherPsoCellBuilder::shader_cache`.
+///
+/// Shaders are keyed by stage, file & content so a rebuild only compiles the stages that
+/// changed, relinking them with the cached others. Cells using the same shader file compile
+/// it once. Shaders no longer used by any cell's pipeline state are dropped.
+///
+/// ```ignore
+/// let cache = ShaderCache::new();
+/// let mut terrain = WatcherPsoCellBuilder::using(terrain_pipe::new())
+///     .vertex_shader("shader/world.vert")
+///     .fragment_shader("shader/terrain.frag")
+///     .shader_cache(&cache)
+///     .build(factory.clone())?;
+/// let mut water = WatcherPsoCellBuilder::using(water_pipe::new())
+///     .vertex_shader("shader/world.vert") // compiled once
+///     .fragment_shader("shader/water.frag")
+///     .shader_cache(&cache)
+///     .build(factory.clone())?;
+/// ```
+#[derive(Clone, Default)]
+pub struct ShaderCache {
+    inner: Arc<Mutex<HashMap<CacheKey, Cached>>>,
+}
+
+impl ShaderCache {
+    pub fn new() -> ShaderCache {
+        ShaderCache::default()
+    }
+
+    /// Returns the number of compiled shaders cached
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the cached shader compiled from `source`, compiling it if not cached
+    pub(crate) fn get_or_compile<S, C>(
+        &self,
+        source: &ShaderSource,
+        compile: C,
+    ) -> Result<S, ShaderWatchError>
+    where
+        S: Any + Send + Clone,
+        C: FnOnce(&ShaderSource) -> Result<S, ShaderWatchError>,
+    {
+        let key = key::<S>(source);
+        if let Some(shader) = self
+            .lock()
+            .get(&key)
+            .and_then(|cached| cached.shader.downcast_ref::<S>())
+        {
+            return Ok(shader.clone());
+        }
+        let shader = compile(source)?;
+        self.lock().insert(
+            key,
+            Cached {
+                shader: Box::new(shader.clone()),
+                users: 0,
+            },
+        );
+        Ok(shader)
+    }
+
+    /// Marks the shaders compiled from `sources` as used until the lease is dropped
+    pub(crate) fn lease<R: Resources>(&self, sources: &Stages<ShaderSource>) -> CacheLease {
+        let keys: Vec<_> = [
+            sources.vertex.as_ref().map(key::<VertexShader<R>>),
+            sources.hull.as_ref().map(key::<HullShader<R>>),
+            sources.domain.as_ref().map(key::<DomainShader<R>>),
+            sources.geometry.as_ref().map(key::<GeometryShader<R>>),
+            sources.fragment.as_ref().map(key::<PixelShader<R>>),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        let mut cached = self.lock();
+        for key in &keys {
+            if let Some(cached) = cached.get_mut(key) {
+                cached.users += 1;
+            }
+        }
+        CacheLease {
+            cache: self.clone(),
+            keys,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<CacheKey, Cached>> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+impl fmt::Debug for ShaderCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShaderCache")
+            .field("shaders", &self.len())
+            .finish()
+    }
+}
+
+struct Cached {
+    shader: Box<dyn Any + Send>,
+    /// Number of leases, ie cells whose active pipeline state uses the shader
+    users: usize,
+}
+
+/// A pipeline state's use of cached shaders, releases them when dropped
+#[derive(Debug)]
+pub(crate) struct CacheLease {
+    cache: ShaderCache,
+    keys: Vec<CacheKey>,
+}
+
+impl Drop for CacheLease {
+    fn drop(&mut self) {
+        let mut cached = self.cache.lock();
+        for key in &self.keys {
+            if let Some(cached) = cached.get_mut(key) {
+                cached.users = cached.users.saturating_sub(1);
+            }
+        }
+        // drop shaders no longer used, ie replaced by a newer version of the file
+        // or compiled by a build that then failed
+        cached.retain(|_, cached| cached.users > 0);
+    }
+}
@@ -0,0 +1,250 @@
+// without the `config-file` feature configs are never parsed, only the default is applied
+#![cfg_attr(not(feature = "config-file"), allow(dead_code))]
+
+use gfx::{state, Primitive};
+#[cfg(feature = "config-file")]
+use {
+    super::ShaderWatchError,
+    serde::Deserialize,
+    std::{fs, path::Path},
+};
+
+/// Pipeline settings of a sidecar config file, unset fields keep the builder's setting
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub(crate) struct PipelineConfig {
+    primitive: Option<PrimitiveName>,
+    /// Control points per patch for the `patch_list` primitive
+    patch_size: Option<u8>,
+    front_face: Option<FrontFace>,
+    cull_face: Option<CullFace>,
+    polygon_mode: Option<PolygonMode>,
+    line_width: Option<state::LineWidth>,
+    /// Polygon offset `[slope, units]`
+    offset: Option<(state::OffsetSlope, state::OffsetUnits)>,
+    multisample: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(Deserialize),
+    serde(rename_all = "snake_case")
+)]
+enum PrimitiveName {
+    PointList,
+    LineList,
+    LineStrip,
+    TriangleList,
+    TriangleStrip,
+    LineListAdjacency,
+    LineStripAdjacency,
+    TriangleListAdjacency,
+    TriangleStripAdjacency,
+    PatchList,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(Deserialize),
+    serde(rename_all = "snake_case")
+)]
+enum FrontFace {
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(Deserialize),
+    serde(rename_all = "snake_case")
+)]
+enum CullFace {
+    Nothing,
+    Front,
+    Back,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(
+    feature = "config-file",
+    derive(Deserialize),
+    serde(rename_all = "snake_case")
+)]
+enum PolygonMode {
+    Point,
+    Line,
+    Fill,
+}
+
+impl PipelineConfig {
+    /// Applies the config's settings over the builder's
+    pub(crate) fn apply(
+        &self,
+        mut primitive: Primitive,
+        mut rasterizer: state::Rasterizer,
+    ) -> (Primitive, state::Rasterizer) {
+        if let Some(name) = self.primitive {
+            primitive = match name {
+                PrimitiveName::PointList => Primitive::PointList,
+                PrimitiveName::LineList => Primitive::LineList,
+                PrimitiveName::LineStrip => Primitive::LineStrip,
+                PrimitiveName::TriangleList => Primitive::TriangleList,
+                PrimitiveName::TriangleStrip => Primitive::TriangleStrip,
+                PrimitiveName::LineListAdjacency => Primitive::LineListAdjacency,
+                PrimitiveName::LineStripAdjacency => Primitive::LineStripAdjacency,
+                PrimitiveName::TriangleListAdjacency => Primitive::TriangleListAdjacency,
+                PrimitiveName::TriangleStripAdjacency => Primitive::TriangleStripAdjacency,
+                PrimitiveName::PatchList => Primitive::PatchList(self.patch_size.unwrap_or(3)),
+            };
+        }
+        if let Some(front_face) = self.front_face {
+            rasterizer.front_face = match front_face {
+                FrontFace::Clockwise => state::FrontFace::Clockwise,
+                FrontFace::CounterClockwise => state::FrontFace::CounterClockwise,
+            };
+        }
+        if let Some(cull_face) = self.cull_face {
+            rasterizer.cull_face = match cull_face {
+                CullFace::Nothing => state::CullFace::Nothing,
+                CullFace::Front => state::CullFace::Front,
+                CullFace::Back => state::CullFace::Back,
+            };
+        }
+        let line_width = match (self.line_width, rasterizer.method) {
+            (Some(width), _) | (None, state::RasterMethod::Line(width)) => width,
+            _ => 1,
+        };
+        match self.polygon_mode {
+            Some(PolygonMode::Point) => rasterizer.method = state::RasterMethod::Point,
+            Some(PolygonMode::Line) => rasterizer.method = state::RasterMethod::Line(line_width),
+            Some(PolygonMode::Fill) => rasterizer.method = state::RasterMethod::Fill,
+            None => {
+                if let state::RasterMethod::Line(_) = rasterizer.method {
+                    rasterizer.method = state::RasterMethod::Line(line_width);
+                }
+            }
+        }
+        if let Some((slope, units)) = self.offset {
+            rasterizer.offset = Some(state::Offset(slope, units));
+        }
+        if let Some(multisample) = self.multisample {
+            rasterizer.samples = multisample.then_some(state::MultiSample);
+        }
+        (primitive, rasterizer)
+    }
+}
+
+/// Reads the pipeline config file
+#[cfg(feature = "config-file")]
+pub(crate) fn load_config(path: &Path) -> Result<PipelineConfig, ShaderWatchError> {
+    let text = fs::read_to_string(path).map_err(|error| ShaderWatchError::Io {
+        path: path.to_path_buf(),
+        error,
+    })?;
+    parse_config(path, &text)
+}
+
+/// Parses pipeline config TOML, errors are located at the `line` & `column` of `path`
+#[cfg(feature = "config-file")]
+fn parse_config(path: &Path, text: &str) -> Result<PipelineConfig, ShaderWatchError> {
+    toml::from_str(text).map_err(|error| {
+        let (line, column) = match error.span() {
+            Some(span) => {
+                let before = &text[..span.start];
+                let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+                (
+                    before.matches('\n').count() + 1,
+                    before[line_start..].chars().count() + 1,
+                )
+            }
+            None => (1, 1),
+        };
+        ShaderWatchError::Config {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message: error.message().into(),
+        }
+    })
+}
+
+#[cfg(all(test, feature = "config-file"))]
+mod test {
+    use super::*;
+
+    fn parse(text: &str) -> Result<PipelineConfig, ShaderWatchError> {
+        parse_config(Path::new("pipeline.toml"), text)
+    }
+
+    #[test]
+    fn apply_settings() {
+        let config = parse(
+            "primitive = \"patch_list\"\n\
+             patch_size = 4\n\
+             cull_face = \"back\"\n\
+             polygon_mode = \"line\"\n\
+             line_width = 2\n\
+             offset = [1, 2]\n\
+             multisample = true\n",
+        )
+        .unwrap();
+        let (primitive, rasterizer) =
+            config.apply(Primitive::TriangleList, state::Rasterizer::new_fill());
+
+        assert_eq!(primitive, Primitive::PatchList(4));
+        assert_eq!(rasterizer.cull_face, state::CullFace::Back);
+        assert_eq!(rasterizer.method, state::RasterMethod::Line(2));
+        assert_eq!(rasterizer.offset, Some(state::Offset(1, 2)));
+        assert_eq!(rasterizer.samples, Some(state::MultiSample));
+        assert_eq!(rasterizer.front_face, state::FrontFace::CounterClockwise);
+    }
+
+    #[test]
+    fn unset_keys_keep_settings() {
+        let config = parse("front_face = \"clockwise\"\n").unwrap();
+        let rasterizer = state::Rasterizer::new_fill().with_cull_back();
+        let (primitive, applied) = config.apply(Primitive::LineStrip, rasterizer);
+
+        assert_eq!(primitive, Primitive::LineStrip);
+        assert_eq!(applied.front_face, state::FrontFace::Clockwise);
+        assert_eq!(applied.cull_face, state::CullFace::Back);
+        assert_eq!(applied.method, state::RasterMethod::Fill);
+    }
+
+    #[test]
+    fn error_span() {
+        let err = parse("cull_face = \"back\"\npolygon_mode = \"wire\"\n").unwrap_err();
+        let ShaderWatchError::Config {
+            path, line, column, ..
+        } = &err
+        else {
+            panic!("expected Config, got {err:?}");
+        };
+        assert_eq!(path, Path::new("pipeline.toml"));
+        assert_eq!((*line, *column), (2, 16));
+        assert!(err.to_string().starts_with("pipeline.toml:2:16: error: "));
+    }
+
+    #[test]
+    fn unknown_key() {
+        let err = parse("\n  cul_face = \"back\"\n").unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ShaderWatchError::Config {
+                    line: 2,
+                    column: 3,
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use super::{source::ShaderSource, ShaderStage};
+use std::{fmt, path::PathBuf};
+
+/// Severity of a shader compiler message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        })
+    }
+}
+
+/// Shader compiler message mapped back to the file & line that caused it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Source file, `None` for compile time shader bytes
+    pub path: Option<PathBuf>,
+    pub stage: ShaderStage,
+    /// 1-based line within `path`
+    pub line: usize,
+    /// 1-based column, when reported by the driver
+    pub column: Option<usize>,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Formats as `path:line:column: severity: message`
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{}:{}", path.display(), self.line)?,
+            None => write!(f, "<{} shader>:{}", self.stage, self.line)?,
+        }
+        if let Some(column) = self.column {
+            write!(f, ":{column}")?;
+        }
+        write!(f, ": {}: {}", self.severity, self.message)
+    }
+}
+
+/// Parses the driver info log of a failed shader compile into diagnostics.
+/// Lines not in a recognised format are skipped.
+pub(crate) fn parse_log(log: &str, stage: ShaderStage, source: &ShaderSource) -> Vec<Diagnostic> {
+    log.lines()
+        .filter_map(|line| {
+            let (line, column, severity, message) = parse_line(line)?;
+            let (path, line) = source
+                .origin(line)
+                .map(|(path, line)| (path.map(PathBuf::from), line))
+                .unwrap_or_else(|| (source.path().map(PathBuf::from), line));
+            Some(Diagnostic {
+                path,
+                stage,
+                line,
+                column,
+                severity,
+                message: message.into(),
+            })
+        })
+        .collect()
+}
+
+/// Returns the non-empty lines of a driver info log that `parse_log` skips
+pub(crate) fn unparsed_lines(log: &str) -> impl Iterator<Item = &str> {
+    log.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && parse_line(line).is_none())
+}
+
+type LogLine<'a> = (usize, Option<usize>, Severity, &'a str);
+
+fn parse_line(line: &str) -> Option<LogLine<'_>> {
+    let line = line.trim();
+    parse_amd(line)
+        .or_else(|| parse_mesa(line))
+        .or_else(|| parse_nvidia(line))
+}
+
+/// AMD, Intel (windows) & Apple: `ERROR: 0:12: message`
+fn parse_amd(line: &str) -> Option<LogLine<'_>> {
+    let (severity, rest) = match line.strip_prefix("ERROR:") {
+        Some(rest) => (Severity::Error, rest),
+        None => (Severity::Warning, line.strip_prefix("WARNING:")?),
+    };
+    let (_, rest) = number(rest.trim_start())?;
+    let (line, rest) = number(rest.strip_prefix(':')?)?;
+    let message = rest.strip_prefix(':')?.trim();
+    Some((line, None, severity, message))
+}
+
+/// Mesa: `0:12(5): error: message`
+fn parse_mesa(line: &str) -> Option<LogLine<'_>> {
+    let (_, rest) = number(line)?;
+    let (line, rest) = number(rest.strip_prefix(':')?)?;
+    let (column, rest) = number(rest.strip_prefix('(')?)?;
+    let (severity, message) = severity(rest.strip_prefix("):")?)?;
+    Some((line, Some(column), severity, message))
+}
+
+/// NVIDIA: `0(12) : error C0000: message`
+fn parse_nvidia(line: &str) -> Option<LogLine<'_>> {
+    let (_, rest) = number(line)?;
+    let (line, rest) = number(rest.strip_prefix('(')?)?;
+    let rest = rest.strip_prefix(')')?.trim_start().strip_prefix(':')?;
+    let (severity, message) = severity(rest)?;
+    Some((line, None, severity, message))
+}
+
+/// Splits leading decimal digits
+fn number(s: &str) -> Option<(usize, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    Some((s[..end].parse().ok()?, &s[end..]))
+}
+
+/// Parses a leading `error`, `warning` or similar returning the remaining message
+fn severity(s: &str) -> Option<(Severity, &str)> {
+    let s = s.trim_start();
+    let s = s.strip_prefix("preprocessor ").unwrap_or(s);
+    let s = s.strip_prefix("fatal ").unwrap_or(s);
+    let (severity, rest) = match s.strip_prefix("error") {
+        Some(rest) => (Severity::Error, rest),
+        None => (Severity::Warning, s.strip_prefix("warning")?),
+    };
+    Some((severity, rest.trim_start_matches(':').trim()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::psocell::source::{expand_includes, load_shader};
+    use std::collections::HashMap;
+
+    #[test]
+    fn mesa() {
+        assert_eq!(
+            parse_mesa("0:12(5): error: `foo' undeclared"),
+            Some((12, Some(5), Severity::Error, "`foo' undeclared"))
+        );
+        assert_eq!(
+            parse_mesa("0:3(10): warning: unused variable"),
+            Some((3, Some(10), Severity::Warning, "unused variable"))
+        );
+        assert_eq!(
+            parse_mesa("0:1(1): preprocessor error: syntax error"),
+            Some((1, Some(1), Severity::Error, "syntax error"))
+        );
+        assert_eq!(parse_mesa("0:12: error: no column"), None);
+    }
+
+    #[test]
+    fn nvidia() {
+        assert_eq!(
+            parse_nvidia("0(12) : error C1008: undefined variable \"foo\""),
+            Some((
+                12,
+                None,
+                Severity::Error,
+                "C1008: undefined variable \"foo\""
+            ))
+        );
+        assert_eq!(
+            parse_nvidia("0(7) : warning C7050: \"c\" might be used before being initialized"),
+            Some((
+                7,
+                None,
+                Severity::Warning,
+                "C7050: \"c\" might be used before being initialized"
+            ))
+        );
+        assert_eq!(parse_nvidia("0(12): note: not a diagnostic"), None);
+    }
+
+    #[test]
+    fn amd() {
+        assert_eq!(
+            parse_amd("ERROR: 0:12: 'foo' : undeclared identifier"),
+            Some((12, None, Severity::Error, "'foo' : undeclared identifier"))
+        );
+        assert_eq!(
+            parse_amd("WARNING: 0:4: extension not supported"),
+            Some((4, None, Severity::Warning, "extension not supported"))
+        );
+        assert_eq!(
+            parse_amd("ERROR: 1 compilation errors.  No code generated."),
+            None
+        );
+    }
+
+    #[test]
+    fn log_lines_map_to_source() {
+        let includes = HashMap::from([("common.glsl".into(), b"float a;\nfloat b;\n".to_vec())]);
+        let source = expand_includes(
+            b"#version 150\n#include \"common.glsl\"\nvoid main() {}\n",
+            &includes,
+        )
+        .unwrap();
+        let log = "0:3(1): error: `b' redeclared\n\
+                   ERROR: 1 compilation errors.  No code generated.\n\
+                   0:4(1): warning: empty main";
+
+        let diagnostics = parse_log(log, ShaderStage::Fragment, &source);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].path.as_deref(), Some("common.glsl".as_ref()));
+        assert_eq!(diagnostics[0].line, 2);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[1].path, None);
+        assert_eq!(diagnostics[1].line, 3);
+        assert_eq!(
+            diagnostics[1].to_string(),
+            "<fragment shader>:3:1: warning: empty main"
+        );
+        assert_eq!(
+            unparsed_lines(log).collect::<Vec<_>>(),
+            ["ERROR: 1 compilation errors.  No code generated."]
+        );
+    }
+
+    #[test]
+    fn unmapped_log_lines_keep_file() {
+        let path = std::env::temp_dir().join("gfx_shader_watch_unmapped.glsl");
+        std::fs::write(&path, "#version 150\nvoid main() {}\n").unwrap();
+        let source = load_shader(&path, &mut Vec::new()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let diagnostics = parse_log(
+            "0:9(1): error: unexpected end",
+            ShaderStage::Vertex,
+            &source,
+        );
+        assert_eq!(diagnostics[0].path.as_deref(), source.path());
+        assert_eq!(diagnostics[0].line, 9);
+    }
+}
@@ -0,0 +1,261 @@
+use super::{
+    diagnostic::unparsed_lines, hub::copy_error, interface::describe_mismatch,
+    source::IncludeError, stages::ShaderStage, Diagnostic,
+};
+use gfx::{
+    pso::InitError,
+    shade::{
+        core::{CreateProgramError, CreateShaderError},
+        ProgramError,
+    },
+    PipelineStateError,
+};
+use std::{error::Error, fmt, io, path::PathBuf};
+
+/// Error building or reloading a pipeline state object
+#[derive(Debug)]
+pub enum ShaderWatchError {
+    /// A required shader stage was not provided
+    MissingStage(ShaderStage),
+    /// A shader file could not be read
+    Io { path: PathBuf, error: io::Error },
+    /// An `#include` directive could not be resolved
+    Include(IncludeError),
+    /// A `#pragma stage` line names an unknown stage, `path` is `None` for compile time
+    /// shader sources
+    UnknownStage {
+        path: Option<PathBuf>,
+        line: usize,
+        name: String,
+    },
+    /// A single file shader has no `#pragma stage` lines, `path` is `None` for compile time
+    /// shader sources
+    NoStageMarkers { path: Option<PathBuf> },
+    /// Watching shader files failed
+    Watcher(notify::Error),
+    /// A shader stage failed to compile, `path` is `None` for compile time shader sources.
+    /// `diagnostics` are parsed from the driver's compile log
+    Compile {
+        stage: ShaderStage,
+        path: Option<PathBuf>,
+        error: CreateShaderError,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// The shader program failed to link
+    Link(CreateProgramError),
+    /// The pipeline config file could not be parsed
+    Config {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// The pipeline definition does not match the shader program, `report` describes how
+    PipelineMismatch {
+        error: InitError<String>,
+        report: String,
+    },
+    /// The device failed to create the pipeline state object
+    PipelineCreate,
+    /// A `Permutation` key is not declared by the `PsoPermutationCell`, or if `value` is
+    /// `Some` the key doesn't take the value
+    UnknownPermutation { name: String, value: Option<String> },
+}
+
+impl ShaderWatchError {
+    /// Returns compile diagnostics, empty for errors other than `Compile`
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            ShaderWatchError::Compile { diagnostics, .. } => diagnostics,
+            _ => &[],
+        }
+    }
+}
+
+/// Io & watcher errors, which aren't `Clone`, are copied by kind & message
+impl Clone for ShaderWatchError {
+    fn clone(&self) -> Self {
+        match self {
+            ShaderWatchError::MissingStage(stage) => ShaderWatchError::MissingStage(*stage),
+            ShaderWatchError::Io { path, error } => ShaderWatchError::Io {
+                path: path.clone(),
+                error: copy_io_error(error),
+            },
+            ShaderWatchError::Include(error) => ShaderWatchError::Include(error.clone()),
+            ShaderWatchError::UnknownStage { path, line, name } => ShaderWatchError::UnknownStage {
+                path: path.clone(),
+                line: *line,
+                name: name.clone(),
+            },
+            ShaderWatchError::NoStageMarkers { path } => {
+                ShaderWatchError::NoStageMarkers { path: path.clone() }
+            }
+            ShaderWatchError::Watcher(error) => ShaderWatchError::Watcher(copy_error(error)),
+            ShaderWatchError::Compile {
+                stage,
+                path,
+                error,
+                diagnostics,
+            } => ShaderWatchError::Compile {
+                stage: *stage,
+                path: path.clone(),
+                error: error.clone(),
+                diagnostics: diagnostics.clone(),
+            },
+            ShaderWatchError::Link(error) => ShaderWatchError::Link(error.clone()),
+            ShaderWatchError::Config {
+                path,
+                line,
+                column,
+                message,
+            } => ShaderWatchError::Config {
+                path: path.clone(),
+                line: *line,
+                column: *column,
+                message: message.clone(),
+            },
+            ShaderWatchError::PipelineMismatch { error, report } => {
+                ShaderWatchError::PipelineMismatch {
+                    error: error.clone(),
+                    report: report.clone(),
+                }
+            }
+            ShaderWatchError::PipelineCreate => ShaderWatchError::PipelineCreate,
+            ShaderWatchError::UnknownPermutation { name, value } => {
+                ShaderWatchError::UnknownPermutation {
+                    name: name.clone(),
+                    value: value.clone(),
+                }
+            }
+        }
+    }
+}
+
+/// Copies an io error by kind & message, as `io::Error` isn't `Clone`
+pub(crate) fn copy_io_error(error: &io::Error) -> io::Error {
+    io::Error::new(error.kind(), error.to_string())
+}
+
+impl fmt::Display for ShaderWatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderWatchError::MissingStage(stage) => write!(f, "missing {stage} shader"),
+            ShaderWatchError::Io { path, error } => write!(f, "{}: {error}", path.display()),
+            ShaderWatchError::Include(error) => write!(f, "{error}"),
+            ShaderWatchError::UnknownStage { path, line, name } => {
+                match path {
+                    Some(path) => write!(f, "{}:{line}: ", path.display())?,
+                    None => write!(f, "line {line}: ")?,
+                }
+                write!(f, "unknown shader stage `{name}`")
+            }
+            ShaderWatchError::NoStageMarkers { path } => {
+                if let Some(path) = path {
+                    write!(f, "{}: ", path.display())?;
+                }
+                write!(f, "no `#pragma stage` markers")
+            }
+            ShaderWatchError::Watcher(error) => write!(f, "watcher error: {error}"),
+            ShaderWatchError::Compile {
+                stage,
+                path,
+                error,
+                diagnostics,
+            } => {
+                if let Some(path) = path {
+                    write!(f, "{}: ", path.display())?;
+                }
+                if diagnostics.is_empty() {
+                    return write!(f, "{stage} shader: {error}");
+                }
+                write!(f, "{stage} shader failed to compile")?;
+                for diagnostic in diagnostics {
+                    write!(f, "\n{diagnostic}")?;
+                }
+                // keep log lines in unrecognised formats, they may hold the actual error
+                if let CreateShaderError::CompilationFailed(log) = error {
+                    for line in unparsed_lines(log) {
+                        write!(f, "\n{line}")?;
+                    }
+                }
+                Ok(())
+            }
+            ShaderWatchError::Link(error) => write!(f, "program link failed: {error}"),
+            ShaderWatchError::Config {
+                path,
+                line,
+                column,
+                message,
+            } => write!(f, "{}:{line}:{column}: error: {message}", path.display()),
+            ShaderWatchError::PipelineMismatch { report, .. } => {
+                write!(f, "pipeline mismatch: {report}")
+            }
+            ShaderWatchError::PipelineCreate => write!(f, "device failed to create pipeline"),
+            ShaderWatchError::UnknownPermutation { name, value } => match value.as_deref() {
+                None => write!(f, "unknown permutation key `{name}`"),
+                Some("") => write!(f, "permutation key `{name}` is not a flag"),
+                Some(value) => write!(f, "permutation key `{name}` has no value `{value}`"),
+            },
+        }
+    }
+}
+
+impl Error for ShaderWatchError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ShaderWatchError::Io { error, .. } => Some(error),
+            ShaderWatchError::Include(error) => Some(error),
+            ShaderWatchError::Watcher(error) => Some(error),
+            ShaderWatchError::Compile { error, .. } => Some(error),
+            ShaderWatchError::Link(error) => Some(error),
+            ShaderWatchError::PipelineMismatch { error, .. } => Some(error),
+            ShaderWatchError::MissingStage(_)
+            | ShaderWatchError::UnknownStage { .. }
+            | ShaderWatchError::NoStageMarkers { .. }
+            | ShaderWatchError::Config { .. }
+            | ShaderWatchError::PipelineCreate
+            | ShaderWatchError::UnknownPermutation { .. } => None,
+        }
+    }
+}
+
+impl From<IncludeError> for ShaderWatchError {
+    fn from(error: IncludeError) -> Self {
+        ShaderWatchError::Include(error)
+    }
+}
+
+impl From<notify::Error> for ShaderWatchError {
+    fn from(error: notify::Error) -> Self {
+        ShaderWatchError::Watcher(error)
+    }
+}
+
+impl From<PipelineStateError<String>> for ShaderWatchError {
+    fn from(error: PipelineStateError<String>) -> Self {
+        match error {
+            PipelineStateError::Program(ProgramError::Link(error)) => ShaderWatchError::Link(error),
+            PipelineStateError::Program(error) => {
+                let (stage, error) = match error {
+                    ProgramError::Vertex(e) => (ShaderStage::Vertex, e),
+                    ProgramError::Hull(e) => (ShaderStage::Hull, e),
+                    ProgramError::Domain(e) => (ShaderStage::Domain, e),
+                    ProgramError::Geometry(e) => (ShaderStage::Geometry, e),
+                    ProgramError::Pixel(e) => (ShaderStage::Fragment, e),
+                    ProgramError::Link(e) => return ShaderWatchError::Link(e),
+                };
+                ShaderWatchError::Compile {
+                    stage,
+                    path: None,
+                    error,
+                    diagnostics: vec![],
+                }
+            }
+            PipelineStateError::DescriptorInit(error) => ShaderWatchError::PipelineMismatch {
+                report: describe_mismatch(&error, None),
+                error,
+            },
+            PipelineStateError::DeviceCreate(_) => ShaderWatchError::PipelineCreate,
+        }
+    }
+}
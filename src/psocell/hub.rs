@@ -0,0 +1,395 @@
+use super::{error::copy_io_error, source::normalize, ShaderWatchError};
+use log::{debug, info, warn};
+use notify::{self, Watcher};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{self, Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// File watching implementation used by `WatcherPsoCell`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WatcherBackend {
+    /// Native file system notifications, ie inotify
+    #[default]
+    Recommended,
+    /// Scans watched directories for changes at an interval. Works where native notifications
+    /// don't fire, ie network, sshfs & some container file systems
+    Poll(Duration),
+    /// Native notifications falling back to polling at the interval if they are unavailable
+    RecommendedOrPoll(Duration),
+}
+
+/// Single file watcher shared by many `WatcherPsoCell`s, see `WatcherPsoCellBuilder::hub`.
+///
+/// Each directory is watched once however many cells depend on it & file changes are
+/// routed only to the cells depending on the changed file.
+///
+/// ```ignore
+/// let hub = ShaderWatchHub::new()?;
+/// let mut terrain = WatcherPsoCellBuilder::using(terrain_pipe::new())
+///     .vertex_shader("shader/terrain.vert")
+///     .fragment_shader("shader/terrain.frag")
+///     .hub(&hub)
+///     .build(factory.clone())?;
+/// let mut water = debug_watcher_pso_cell!(
+///     pipe = water_pipe,
+///     vertex_shader = "shader/water.vert",
+///     fragment_shader = "shader/water.frag",
+///     factory = factory.clone(),
+///     hub = &hub)?;
+/// ```
+#[derive(Clone)]
+pub struct ShaderWatchHub {
+    inner: Arc<Mutex<Hub>>,
+}
+
+impl ShaderWatchHub {
+    /// Creates a hub watching with native file system notifications
+    pub fn new() -> Result<ShaderWatchHub, ShaderWatchError> {
+        ShaderWatchHub::with_backend(WatcherBackend::default())
+    }
+
+    pub fn with_backend(backend: WatcherBackend) -> Result<ShaderWatchHub, ShaderWatchError> {
+        Ok(ShaderWatchHub {
+            inner: Arc::new(Mutex::new(Hub::new(backend)?)),
+        })
+    }
+
+    /// Returns the number of directories being watched
+    pub fn watched_dirs(&self) -> usize {
+        self.lock().dirs.len()
+    }
+
+    pub(crate) fn subscribe(&self) -> Subscription {
+        let mut hub = self.lock();
+        let id = hub.next_id;
+        hub.next_id += 1;
+        hub.subscribers.insert(id, Subscriber::default());
+        Subscription {
+            hub: self.clone(),
+            id,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Hub> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+impl fmt::Debug for ShaderWatchHub {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hub = self.lock();
+        f.debug_struct("ShaderWatchHub")
+            .field("backend", &hub.backend)
+            .field("dirs", &hub.dirs)
+            .field("subscribers", &hub.subscribers.len())
+            .finish()
+    }
+}
+
+/// A cell's interest in the hub, stops watching its files when dropped
+#[derive(Debug)]
+pub(crate) struct Subscription {
+    hub: ShaderWatchHub,
+    id: u64,
+}
+
+impl Subscription {
+    /// Watches each file & its directory, if not already watched. Relative paths are
+    /// resolved against the current directory, as events are reported with absolute paths
+    pub(crate) fn watch(&mut self, files: &[PathBuf]) -> notify::Result<()> {
+        let mut hub = self.hub.lock();
+        for file in files {
+            let file = normalize(&path::absolute(file).map_err(notify::Error::io)?);
+            let dir = file.parent().unwrap_or(&file);
+            if !hub.subscribers[&self.id].dirs.contains(dir) {
+                hub.watch_dir(dir)?;
+            }
+            let subscriber = hub.subscribers.get_mut(&self.id).expect("subscribed");
+            subscriber.dirs.insert(dir.to_path_buf());
+            subscriber.files.insert(file);
+        }
+        Ok(())
+    }
+
+    /// Returns which watched files have been modified, created, removed or renamed
+    /// since the last call along with any watcher errors
+    pub(crate) fn recv_changes(&mut self) -> (Vec<PathBuf>, Vec<notify::Error>) {
+        let mut hub = self.hub.lock();
+        hub.dispatch();
+        let subscriber = hub.subscribers.get_mut(&self.id).expect("subscribed");
+        (
+            std::mem::take(&mut subscriber.changed),
+            std::mem::take(&mut subscriber.errors),
+        )
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut hub = self.hub.lock();
+        if let Some(subscriber) = hub.subscribers.remove(&self.id) {
+            for dir in subscriber.dirs {
+                hub.unwatch_dir(&dir);
+            }
+        }
+    }
+}
+
+/// Files a cell depends on & the changes routed to it not yet received
+#[derive(Debug, Default)]
+struct Subscriber {
+    files: HashSet<PathBuf>,
+    dirs: HashSet<PathBuf>,
+    changed: Vec<PathBuf>,
+    errors: Vec<notify::Error>,
+}
+
+impl Subscriber {
+    fn change(&mut self, path: &Path) {
+        if !self.changed.iter().any(|p| p == path) {
+            self.changed.push(path.to_path_buf());
+        }
+    }
+
+    /// Marks each file in the directory as changed
+    fn change_dir(&mut self, dir: &Path) {
+        let Subscriber { files, changed, .. } = self;
+        for file in files.iter().filter(|f| f.parent() == Some(dir)) {
+            if !changed.contains(file) {
+                changed.push(file.clone());
+            }
+        }
+    }
+}
+
+struct Hub {
+    watcher: Box<dyn Watcher + Send>,
+    backend: WatcherBackend,
+    /// Sender for the events of a fallback watcher
+    tx: mpsc::Sender<notify::Result<notify::Event>>,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    /// Watched directories & the number of subscribers with files in each
+    dirs: HashMap<PathBuf, usize>,
+    /// Watched directories that were removed, moved or didn't exist yet,
+    /// to watch once (re)created
+    lost_dirs: HashSet<PathBuf>,
+    subscribers: HashMap<u64, Subscriber>,
+    next_id: u64,
+}
+
+impl Hub {
+    fn new(backend: WatcherBackend) -> notify::Result<Hub> {
+        let (tx, events) = mpsc::channel();
+        let (watcher, backend) = match backend {
+            WatcherBackend::RecommendedOrPoll(interval) => {
+                match new_watcher(WatcherBackend::Recommended, &tx) {
+                    Ok(watcher) => (watcher, backend),
+                    Err(err) => {
+                        warn!("Native file watching unavailable, {err}");
+                        let backend = WatcherBackend::Poll(interval);
+                        (new_watcher(backend, &tx)?, backend)
+                    }
+                }
+            }
+            _ => (new_watcher(backend, &tx)?, backend),
+        };
+        log_backend(backend);
+
+        Ok(Hub {
+            watcher,
+            backend,
+            tx,
+            events,
+            dirs: HashMap::new(),
+            lost_dirs: HashSet::new(),
+            subscribers: HashMap::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Replaces the native watcher with a polling one if configured to fall back
+    fn fall_back_to_polling(&mut self) -> notify::Result<bool> {
+        let WatcherBackend::RecommendedOrPoll(interval) = self.backend else {
+            return Ok(false);
+        };
+        self.backend = WatcherBackend::Poll(interval);
+        self.watcher = new_watcher(self.backend, &self.tx)?;
+        log_backend(self.backend);
+        for dir in self.dirs.keys().filter(|d| !self.lost_dirs.contains(*d)) {
+            self.watcher
+                .watch(dir, notify::RecursiveMode::NonRecursive)?;
+        }
+        Ok(true)
+    }
+
+    /// Adds a subscriber to the directory, watching it if it's the first
+    fn watch_dir(&mut self, dir: &Path) -> notify::Result<()> {
+        if let Some(count) = self.dirs.get_mut(dir) {
+            *count += 1;
+            return Ok(());
+        }
+
+        if !dir.is_dir() {
+            debug!("{dir:?} missing, waiting for it to be created");
+            self.lost_dirs.insert(dir.to_path_buf());
+        } else {
+            debug!("Watching {dir:?}");
+            if let Err(err) = self.watcher.watch(dir, notify::RecursiveMode::NonRecursive) {
+                if !self.fall_back_to_polling()? {
+                    return Err(err);
+                }
+                warn!("Native file watching failed, {err}");
+                self.watcher
+                    .watch(dir, notify::RecursiveMode::NonRecursive)?;
+            }
+        }
+        self.dirs.insert(dir.to_path_buf(), 1);
+        Ok(())
+    }
+
+    /// Removes a subscriber from the directory, unwatching it if it was the last
+    fn unwatch_dir(&mut self, dir: &Path) {
+        let Some(count) = self.dirs.get_mut(dir) else {
+            return;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        self.dirs.remove(dir);
+        if !self.lost_dirs.remove(dir) {
+            debug!("Unwatching {dir:?}");
+            if let Err(err) = self.watcher.unwatch(dir) {
+                debug!("Unwatching {dir:?} failed, {err}");
+            }
+        }
+    }
+
+    /// Routes received events to the subscribers depending on the changed files
+    fn dispatch(&mut self) {
+        while let Ok(event) = self.events.try_recv() {
+            let event = match event {
+                Ok(event) => event,
+                Err(err) => {
+                    let mut recipients = self
+                        .subscribers
+                        .values_mut()
+                        .filter(|s| {
+                            err.paths.is_empty()
+                                || err.paths.iter().any(|p| {
+                                    s.files.contains(p) || s.dirs.iter().any(|d| p.starts_with(d))
+                                })
+                        })
+                        .peekable();
+                    while let Some(subscriber) = recipients.next() {
+                        match recipients.peek() {
+                            Some(_) => subscriber.errors.push(copy_error(&err)),
+                            None => {
+                                subscriber.errors.push(err);
+                                break;
+                            }
+                        }
+                    }
+                    continue;
+                }
+            };
+            if event.need_rescan() {
+                // events were missed, assume everything changed
+                for subscriber in self.subscribers.values_mut() {
+                    subscriber.changed = subscriber.files.iter().cloned().collect();
+                }
+                continue;
+            }
+            if !(event.kind.is_modify() || event.kind.is_create() || event.kind.is_remove()) {
+                continue;
+            }
+            for path in event.paths {
+                if self.dirs.contains_key(&path)
+                    && !self.lost_dirs.contains(&path)
+                    && !path.is_dir()
+                {
+                    // the watch is removed along with the directory
+                    debug!("{path:?} removed, waiting for it to be recreated");
+                    for subscriber in self.subscribers.values_mut() {
+                        if subscriber.dirs.contains(&path) {
+                            subscriber.change_dir(&path);
+                        }
+                    }
+                    self.lost_dirs.insert(path);
+                } else {
+                    for subscriber in self.subscribers.values_mut() {
+                        if subscriber.files.contains(&path) {
+                            subscriber.change(&path);
+                        }
+                    }
+                }
+            }
+        }
+
+        let Hub {
+            watcher,
+            lost_dirs,
+            subscribers,
+            ..
+        } = self;
+        lost_dirs.retain(|dir| {
+            if !dir.is_dir() {
+                return true;
+            }
+            let result = watcher.watch(dir, notify::RecursiveMode::NonRecursive);
+            let subscribers = subscribers.values_mut().filter(|s| s.dirs.contains(dir));
+            match result {
+                Ok(()) => {
+                    debug!("{dir:?} created, watching");
+                    subscribers.for_each(|s| s.change_dir(dir));
+                    false
+                }
+                Err(err) => {
+                    subscribers.for_each(|s| s.errors.push(copy_error(&err)));
+                    true
+                }
+            }
+        });
+    }
+}
+
+fn new_watcher(
+    backend: WatcherBackend,
+    tx: &mpsc::Sender<notify::Result<notify::Event>>,
+) -> notify::Result<Box<dyn Watcher + Send>> {
+    let config = notify::Config::default();
+    Ok(match backend {
+        WatcherBackend::Poll(interval) => Box::new(notify::PollWatcher::new(
+            tx.clone(),
+            config.with_poll_interval(interval),
+        )?),
+        _ => Box::new(notify::RecommendedWatcher::new(tx.clone(), config)?),
+    })
+}
+
+fn log_backend(backend: WatcherBackend) {
+    match backend {
+        WatcherBackend::Poll(interval) => info!("Watching shaders by polling every {interval:?}"),
+        _ => info!("Watching shaders with native file notifications"),
+    }
+}
+
+/// Copies an error for each subscriber it concerns, `notify::Error` isn't `Clone`
+pub(crate) fn copy_error(err: &notify::Error) -> notify::Error {
+    use notify::ErrorKind;
+    let kind = match &err.kind {
+        ErrorKind::Generic(msg) => ErrorKind::Generic(msg.clone()),
+        ErrorKind::Io(err) => ErrorKind::Io(copy_io_error(err)),
+        ErrorKind::PathNotFound => ErrorKind::PathNotFound,
+        ErrorKind::WatchNotFound => ErrorKind::WatchNotFound,
+        ErrorKind::InvalidConfig(config) => ErrorKind::InvalidConfig(*config),
+        ErrorKind::MaxFilesWatch => ErrorKind::MaxFilesWatch,
+    };
+    notify::Error::new(kind).set_paths(err.paths.clone())
+}
@@ -0,0 +1,257 @@
+use gfx::{
+    format::Format,
+    pso::{ElementError, InitError},
+    shade::{
+        core::{BaseType, ContainerType},
+        ConstFormat,
+    },
+    ProgramInfo,
+};
+use std::fmt::{self, Write};
+
+/// Describes a mismatch between a pipeline definition & the linked shader program,
+/// ie "pipeline expects vertex attribute `pos` but shader declares `a_pos`"
+pub(crate) fn describe_mismatch(
+    error: &InitError<String>,
+    program: Option<&ProgramInfo>,
+) -> String {
+    let Some(program) = program else {
+        return error.to_string();
+    };
+    let mut report = String::new();
+    // writing to a string doesn't fail
+    let _ = write_mismatch(&mut report, error, program);
+    report
+}
+
+fn write_mismatch(
+    report: &mut String,
+    error: &InitError<String>,
+    program: &ProgramInfo,
+) -> fmt::Result {
+    let declared = |names: &mut dyn Iterator<Item = &str>| {
+        let names: Vec<_> = names.map(|name| format!("`{name}`")).collect();
+        match names.is_empty() {
+            true => "none".to_owned(),
+            false => names.join(", "),
+        }
+    };
+
+    match error {
+        InitError::VertexImport(name, None) => write!(
+            report,
+            "pipeline expects vertex attribute `{name}` but shader declares {}",
+            declared(&mut program.vertex_attributes.iter().map(|a| a.name.as_str()))
+        ),
+        InitError::VertexImport(name, Some(format)) => {
+            write!(
+                report,
+                "vertex attribute `{name}` has format {}",
+                format_name(format)
+            )?;
+            match program.vertex_attributes.iter().find(|a| &a.name == name) {
+                Some(attr) => write!(
+                    report,
+                    " incompatible with shader type {}",
+                    glsl_type((attr.base_type, attr.container))
+                ),
+                None => write!(report, " incompatible with the shader"),
+            }
+        }
+        InitError::ConstantBuffer(name, None) => write!(
+            report,
+            "pipeline expects constant buffer `{name}` but shader declares {}",
+            declared(&mut program.constant_buffers.iter().map(|b| b.name.as_str()))
+        ),
+        InitError::ConstantBuffer(buffer, Some(ElementError::NotFound(field))) => {
+            let elements = program
+                .constant_buffers
+                .iter()
+                .find(|b| &b.name == buffer)
+                .map(|b| b.elements.as_slice())
+                .unwrap_or_default();
+            write!(
+                report,
+                "pipeline expects constant buffer `{buffer}` field `{field}` but shader declares {}",
+                declared(&mut elements.iter().map(|e| e.name.as_str()))
+            )
+        }
+        InitError::ConstantBuffer(
+            buffer,
+            Some(ElementError::Offset {
+                name,
+                shader_offset,
+                code_offset,
+            }),
+        ) => write!(
+            report,
+            "constant buffer `{buffer}` field `{name}` has offset {shader_offset}, expected {code_offset}"
+        ),
+        InitError::ConstantBuffer(
+            buffer,
+            Some(ElementError::Format {
+                name,
+                shader_format,
+                code_format,
+            }),
+        ) => write!(
+            report,
+            "constant buffer `{buffer}` field `{name}` has type {}, expected {}",
+            glsl_type(*shader_format),
+            glsl_type(*code_format)
+        ),
+        InitError::GlobalConstant(name, None) => write!(
+            report,
+            "pipeline expects uniform `{name}` but shader declares {}",
+            declared(&mut program.globals.iter().map(|g| g.name.as_str()))
+        ),
+        InitError::GlobalConstant(name, Some(error)) => {
+            write!(report, "uniform `{name}`")?;
+            if let Some(global) = program.globals.iter().find(|g| &g.name == name) {
+                write!(
+                    report,
+                    " has type {}",
+                    glsl_type((global.base_type, global.container))
+                )?;
+            }
+            write!(report, ", {error}")
+        }
+        InitError::ResourceView(name, None) => write!(
+            report,
+            "pipeline expects texture `{name}` but shader declares {}",
+            declared(&mut program.textures.iter().map(|t| t.name.as_str()))
+        ),
+        InitError::ResourceView(name, Some(())) => {
+            write!(report, "texture `{name}` is not compatible with the shader")
+        }
+        InitError::UnorderedView(name, None) => write!(
+            report,
+            "pipeline expects unordered access view `{name}` but shader declares {}",
+            declared(&mut program.unordereds.iter().map(|u| u.name.as_str()))
+        ),
+        InitError::UnorderedView(name, Some(())) => write!(
+            report,
+            "unordered access view `{name}` is not compatible with the shader"
+        ),
+        InitError::Sampler(name, None) => write!(
+            report,
+            "pipeline expects sampler `{name}` but shader declares {}",
+            declared(&mut program.samplers.iter().map(|s| s.name.as_str()))
+        ),
+        InitError::Sampler(name, Some(())) => {
+            write!(report, "sampler `{name}` is not compatible with the shader")
+        }
+        InitError::PixelExport(name, None) => write!(
+            report,
+            "pipeline expects output `{name}` but shader declares {}",
+            declared(&mut program.outputs.iter().map(|o| o.name.as_str()))
+        ),
+        InitError::PixelExport(name, Some(format)) => {
+            write!(report, "output `{name}` has format {}", format_name(format))?;
+            match program.outputs.iter().find(|o| &o.name == name) {
+                Some(output) => write!(
+                    report,
+                    " incompatible with shader type {}",
+                    glsl_type((output.base_type, output.container))
+                ),
+                None => write!(report, " incompatible with the shader"),
+            }
+        }
+    }
+}
+
+/// Returns the GLSL name of a shader type, ie `vec3` or `mat4`
+fn glsl_type((base, container): ConstFormat) -> String {
+    let (scalar, prefix) = match base {
+        BaseType::I32 => ("int", "i"),
+        BaseType::U32 => ("uint", "u"),
+        BaseType::F32 => ("float", ""),
+        BaseType::F64 => ("double", "d"),
+        BaseType::Bool => ("bool", "b"),
+    };
+    match container {
+        ContainerType::Single => scalar.to_owned(),
+        ContainerType::Vector(n) => format!("{prefix}vec{n}"),
+        ContainerType::Matrix(_, cols, rows) if cols == rows => format!("{prefix}mat{cols}"),
+        ContainerType::Matrix(_, cols, rows) => format!("{prefix}mat{cols}x{rows}"),
+    }
+}
+
+fn format_name(Format(surface, channel): &Format) -> String {
+    format!("{surface:?} {channel:?}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use gfx::shade::core::{AttributeVar, ConstVar, ConstantBufferVar, MatrixFormat, Usage};
+
+    fn program() -> ProgramInfo {
+        ProgramInfo {
+            vertex_attributes: vec![AttributeVar {
+                name: "a_pos".into(),
+                slot: 0,
+                base_type: BaseType::F32,
+                container: ContainerType::Vector(3),
+            }],
+            globals: vec![],
+            constant_buffers: vec![ConstantBufferVar {
+                name: "Locals".into(),
+                slot: 0,
+                size: 16,
+                usage: Usage::VERTEX,
+                elements: vec![ConstVar {
+                    name: "u_scale".into(),
+                    location: 0,
+                    count: 1,
+                    base_type: BaseType::F32,
+                    container: ContainerType::Vector(3),
+                }],
+            }],
+            textures: vec![],
+            unordereds: vec![],
+            samplers: vec![],
+            outputs: vec![],
+            output_depth: false,
+            knows_outputs: true,
+        }
+    }
+
+    #[test]
+    fn vertex_attribute_name_mismatch() {
+        let error = InitError::VertexImport("pos".into(), None);
+        assert_eq!(
+            describe_mismatch(&error, Some(&program())),
+            "pipeline expects vertex attribute `pos` but shader declares `a_pos`"
+        );
+        assert_eq!(describe_mismatch(&error, None), error.to_string());
+    }
+
+    #[test]
+    fn constant_buffer_field_type_mismatch() {
+        let error = InitError::ConstantBuffer(
+            "Locals".into(),
+            Some(ElementError::Format {
+                name: "u_scale".into(),
+                shader_format: (BaseType::F32, ContainerType::Vector(3)),
+                code_format: (BaseType::F32, ContainerType::Single),
+            }),
+        );
+        assert_eq!(
+            describe_mismatch(&error, Some(&program())),
+            "constant buffer `Locals` field `u_scale` has type vec3, expected float"
+        );
+    }
+
+    #[test]
+    fn glsl_types() {
+        let matrix = |cols, rows| ContainerType::Matrix(MatrixFormat::ColumnMajor, cols, rows);
+        assert_eq!(glsl_type((BaseType::I32, ContainerType::Single)), "int");
+        assert_eq!(
+            glsl_type((BaseType::U32, ContainerType::Vector(2))),
+            "uvec2"
+        );
+        assert_eq!(glsl_type((BaseType::F32, matrix(4, 4))), "mat4");
+        assert_eq!(glsl_type((BaseType::F64, matrix(2, 3))), "dmat2x3");
+    }
+}
@@ -12,6 +12,17 @@ macro_rules! debug_watcher_pso_cell_type {
 /// `SimplePsoCell` instance when compiled in release mode
 /// The type itself can be attained similarly with the `debug_watcher_pso_cell_type` macro
 ///
+/// Shader stages `vertex_shader`, `hull_shader`, `domain_shader`, `geometry_shader` &
+/// `fragment_shader` are given as paths relative to the calling source file, followed by the
+/// `factory` & any other builder options, a `config_file` is also relative to the calling
+/// source file. A single file `shader` split into stages by `#pragma stage <name>` lines
+/// may be given instead.
+///
+/// Files the shaders `#include` are given before the `factory` as
+/// `include_source = (include_path, path)`, where `include_path` is relative to the root shader
+/// & `path` relative to the calling source file. These are embedded in release mode &
+/// ignored in debug mode, where included files are read from disk.
+///
 /// # Examples
 /// ```ignore
 /// gfx_defines! {
@@ -26,51 +37,128 @@ macro_rules! debug_watcher_pso_cell_type {
 ///    let mut _pso_cell = debug_watcher_pso_cell!(
 ///        pipe = mypipeline,
 ///        vertex_shader = "shader/vert.glsl", // relative to this file
+///        geometry_shader = "shader/geom.glsl", // optional
 ///        fragment_shader = "shader/frag.glsl",
+///        include_source = ("common/light.glsl", "shader/common/light.glsl"), // #include-d
+///        factory = factory.clone()).expect("psocell");
+///
+///    let mut _single_file_cell = debug_watcher_pso_cell!(
+///        pipe = mypipeline,
+///        shader = "shader/triangle.glsl", // `#pragma stage vertex` ...
 ///        factory = factory).expect("psocell");
 /// }
 /// ```
 #[cfg(debug_assertions)]
 #[macro_export]
 macro_rules! debug_watcher_pso_cell {
-    (pipe = $pipe_name:ident,
-    vertex_shader = $vs:expr,
-    fragment_shader = $fs:expr,
-    factory = $factory:expr,
-    $($opt:ident = $opt_val:expr),+) => {{
+    (pipe = $pipe_name:ident, $($args:tt)+) => {
+        $crate::debug_watcher_pso_cell!(
+            @with $crate::WatcherPsoCellBuilder::build,
+            pipe = $pipe_name,
+            $($args)+)
+    };
+
+    (@with $build:path, pipe = $pipe_name:ident, $($args:tt)+) => {{
         use std::path::Path;
         use $crate::WatcherPsoCellBuilder;
 
         match Path::new(file!()).canonicalize() {
-            Ok(path) => match path.parent().ok_or("Could not find current dir") {
-                Ok(dir) => {
-                    let vs = $vs.split("/").fold(dir.to_path_buf(), |path, s| path.join(s));
-                    let fs = $fs.split("/").fold(dir.to_path_buf(), |path, s| path.join(s));
-                    WatcherPsoCellBuilder::using($pipe_name::new())
-                        .vertex_shader(vs)
-                        .fragment_shader(fs)
-                        $(.$opt($opt_val))+
-                        .build($factory)
-                },
-                Err(err) => Err(err.into())
-            },
-            Err(err) => Err(err.into())
+            Ok(path) => {
+                let dir = path.parent().unwrap_or(&path);
+                $crate::debug_watcher_pso_cell!(
+                    @build dir,
+                    $build,
+                    WatcherPsoCellBuilder::using($pipe_name::new()),
+                    $($args)+)
+            }
+            Err(error) => Err($crate::ShaderWatchError::Io { path: file!().into(), error }),
         }
     }};
 
-    (pipe = $pipe_name:ident,
-    vertex_shader = $vs:expr,
-    fragment_shader = $fs:expr,
-    factory = $factory:expr) => {{
-        use gfx::Primitive;
-        debug_watcher_pso_cell!(pipe = $pipe_name,
-                                vertex_shader = $vs,
-                                fragment_shader = $fs,
-                                factory = $factory,
-                                primitive = Primitive::TriangleList)
+    (@build $dir:ident,
+    $build:path,
+    $builder:expr,
+    factory = $factory:expr $(,)?) => {
+        $build($builder, $factory)
+    };
+
+    // the config file is relative to the calling source file, like shaders
+    (@build $dir:ident,
+    $build:path,
+    $builder:expr,
+    factory = $factory:expr,
+    config_file = $path:expr
+    $(, $($rest:tt)*)?) => {{
+        let config = $path.split("/").fold($dir.to_path_buf(), |path, s| path.join(s));
+        $crate::debug_watcher_pso_cell!(
+            @build $dir, $build, $builder.config_file(config), factory = $factory $(, $($rest)*)?)
+    }};
+
+    (@build $dir:ident,
+    $build:path,
+    $builder:expr,
+    factory = $factory:expr,
+    $opt:ident = $opt_val:expr
+    $(, $($rest:tt)*)?) => {
+        $crate::debug_watcher_pso_cell!(
+            @build $dir, $build, $builder.$opt($opt_val), factory = $factory $(, $($rest)*)?)
+    };
+
+    // included files are read from disk
+    (@build $dir:ident,
+    $build:path,
+    $builder:expr,
+    include_source = ($include:expr, $path:expr),
+    $($rest:tt)+) => {
+        $crate::debug_watcher_pso_cell!(@build $dir, $build, $builder, $($rest)+)
+    };
+
+    (@build $dir:ident,
+    $build:path,
+    $builder:expr,
+    $stage:ident = $path:expr,
+    $($rest:tt)+) => {{
+        let shader = $path.split("/").fold($dir.to_path_buf(), |path, s| path.join(s));
+        $crate::debug_watcher_pso_cell!(@build $dir, $build, $builder.$stage(shader), $($rest)+)
     }};
 }
 
+/// Returns `PsoPermutationCell` type of `WatcherPsoCell`s when compiled in debug mode,
+/// of `SimplePsoCell`s when compiled in release mode
+/// Type will match that returned by `debug_pso_permutation_cell` macro
+#[cfg(debug_assertions)]
+#[macro_export]
+macro_rules! debug_pso_permutation_cell_type {
+    ($r_type:ty, $f_type:ty, pipe = $pipe_name:ident) =>
+        (PsoPermutationCell<$r_type, $f_type, WatcherPsoCellBuilder<$pipe_name::Init<'static>>>)
+}
+
+/// Returns `PsoPermutationCell` of `WatcherPsoCell`s when compiled in debug mode,
+/// of `SimplePsoCell`s when compiled in release mode, taking the same arguments as
+/// `debug_watcher_pso_cell`. Declare the permutation keys on the returned cell
+///
+/// # Examples
+/// ```ignore
+/// let mut cell = debug_pso_permutation_cell!(
+///     pipe = mypipeline,
+///     vertex_shader = "shader/vert.glsl",
+///     fragment_shader = "shader/frag.glsl",
+///     factory = factory)
+///     .expect("psocell")
+///     .flag("SHADOWS")
+///     .key("MAX_LIGHTS", ["1", "4", "8"]);
+/// ```
+#[cfg(debug_assertions)]
+#[macro_export]
+macro_rules! debug_pso_permutation_cell {
+    (pipe = $pipe_name:ident, $($args:tt)+) => {
+        $crate::debug_watcher_pso_cell!(
+            @with $crate::PsoPermutationCell::new,
+            pipe = $pipe_name,
+            $($args)+)
+    };
+}
+
 #[cfg(not(debug_assertions))]
 #[macro_export]
 macro_rules! debug_watcher_pso_cell_type {
@@ -81,25 +169,68 @@ macro_rules! debug_watcher_pso_cell_type {
 #[cfg(not(debug_assertions))]
 #[macro_export]
 macro_rules! debug_watcher_pso_cell {
-    (pipe = $pipe_name:ident,
-    vertex_shader = $vs:expr,
-    fragment_shader = $fs:expr,
-    factory = $factory:expr) => {{
-        $crate::SimplePsoCellBuilder::using($pipe_name::new())
-            .vertex_shader(include_bytes!($vs))
-            .fragment_shader(include_bytes!($fs))
-            .build($factory)
-    }};
+    (pipe = $pipe_name:ident, $($args:tt)+) => {
+        $crate::debug_watcher_pso_cell!(
+            @with $crate::SimplePsoCellBuilder::build,
+            pipe = $pipe_name,
+            $($args)+)
+    };
 
-    (pipe = $pipe_name:ident,
-    vertex_shader = $vs:expr,
-    fragment_shader = $fs:expr,
-    factory = $factory:expr,
-    $($opt:ident = $opt_val:expr),+) => {{
-        $crate::SimplePsoCellBuilder::using($pipe_name::new())
-            .vertex_shader(include_bytes!($vs))
-            .fragment_shader(include_bytes!($fs))
-            $(.$opt($opt_val))+
-            .build($factory)
+    (@with $build:path, pipe = $pipe_name:ident, $($args:tt)+) => {{
+        $crate::debug_watcher_pso_cell!(
+            @build
+            $build,
+            $crate::SimplePsoCellBuilder::using($pipe_name::new()),
+            $($args)+)
     }};
+
+    (@build
+    $build:path,
+    $builder:expr,
+    factory = $factory:expr
+    $(, $opt:ident = $opt_val:expr)* $(,)?) => {
+        $build($builder $(.$opt($opt_val))*, $factory)
+    };
+
+    (@build
+    $build:path,
+    $builder:expr,
+    include_source = ($include:expr, $path:expr),
+    $($rest:tt)+) => {
+        $crate::debug_watcher_pso_cell!(
+            @build
+            $build,
+            $builder.include_source($include, include_bytes!($path)),
+            $($rest)+)
+    };
+
+    (@build
+    $build:path,
+    $builder:expr,
+    $stage:ident = $path:expr,
+    $($rest:tt)+) => {
+        $crate::debug_watcher_pso_cell!(
+            @build
+            $build,
+            $builder.$stage(include_bytes!($path)),
+            $($rest)+)
+    };
+}
+
+#[cfg(not(debug_assertions))]
+#[macro_export]
+macro_rules! debug_pso_permutation_cell_type {
+    ($r_type:ty, $f_type:ty, pipe = $pipe_name:ident) =>
+        (PsoPermutationCell<$r_type, $f_type, SimplePsoCellBuilder<$pipe_name::Init<'static>>>)
+}
+
+#[cfg(not(debug_assertions))]
+#[macro_export]
+macro_rules! debug_pso_permutation_cell {
+    (pipe = $pipe_name:ident, $($args:tt)+) => {
+        $crate::debug_watcher_pso_cell!(
+            @with $crate::PsoPermutationCell::new,
+            pipe = $pipe_name,
+            $($args)+)
+    };
 }
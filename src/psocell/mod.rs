@@ -1,16 +1,57 @@
+mod cache;
+mod config;
+mod diagnostic;
+mod error;
+mod hub;
+mod interface;
+mod overrides;
+mod permutation;
+mod reload;
+mod source;
+mod stages;
 mod watcher;
 #[macro_use]
 pub mod macros;
 
-pub use crate::psocell::watcher::{WatcherPsoCell, WatcherPsoCellBuilder};
+pub use crate::psocell::{
+    cache::ShaderCache,
+    diagnostic::{Diagnostic, Severity},
+    error::ShaderWatchError,
+    hub::{ShaderWatchHub, WatcherBackend},
+    overrides::{rasterizer_override, set_rasterizer_override, RasterizerOverride},
+    permutation::{Permutation, PermutationBuilder, PsoPermutationCell},
+    reload::mark_all_dirty,
+    source::IncludeError,
+    stages::ShaderStage,
+    watcher::{WatcherPsoCell, WatcherPsoCellBuilder},
+};
 
-use gfx::{traits::FactoryExt, *};
-use std::error::Error;
+use crate::psocell::{
+    cache::CacheLease,
+    source::{expand_includes, normalize, ShaderSource},
+    stages::Stages,
+};
+use gfx::*;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 /// Container for pipeline state object and a factory
 pub trait PsoCell<R: Resources, F: Factory<R>, I: pso::PipelineInit> {
     fn pso(&mut self) -> &mut PipelineState<R, I::Meta>;
     fn factory(&mut self) -> &mut F;
+
+    /// Returns the pipeline state & `true` if this call replaced it with a newly built one
+    fn pso_with_status(&mut self) -> (&mut PipelineState<R, I::Meta>, bool) {
+        (self.pso(), false)
+    }
+
+    /// Number of times the pipeline state has been replaced since the cell was built
+    fn generation(&self) -> u64 {
+        0
+    }
 }
 
 /// Container with compile time shader source, pipeline state is build on initialisation
@@ -18,6 +59,66 @@ pub trait PsoCell<R: Resources, F: Factory<R>, I: pso::PipelineInit> {
 pub struct SimplePsoCell<R: Resources, F: Factory<R>, I: pso::PipelineInit> {
     pso: PipelineState<R, I::Meta>,
     factory: F,
+    sources: Stages<ShaderSource>,
+    cache: ShaderCache,
+    /// Keeps the compiled shaders of `sources` cached for rebuilds
+    _lease: CacheLease,
+    program: ProgramInfo,
+    primitive: Primitive,
+    rasterizer: state::Rasterizer,
+    init: I,
+    generation: u64,
+}
+
+impl<R: Resources, F: Factory<R>, I: pso::PipelineInit + Clone> SimplePsoCell<R, F, I> {
+    /// Returns the reflected interface of the shader program,
+    /// ie its vertex attributes, constant buffers, textures & outputs
+    pub fn program_info(&self) -> &ProgramInfo {
+        &self.program
+    }
+
+    /// Rebuilds the pipeline state with a new primitive, on failure the previous
+    /// pipeline state & primitive are kept
+    pub fn set_primitive(&mut self, primitive: Primitive) -> Result<(), ShaderWatchError> {
+        self.rebuild(primitive, self.rasterizer, self.init.clone())
+    }
+
+    /// Rebuilds the pipeline state with a new rasterizer, on failure the previous
+    /// pipeline state & rasterizer are kept
+    pub fn set_rasterizer(
+        &mut self,
+        rasterizer: state::Rasterizer,
+    ) -> Result<(), ShaderWatchError> {
+        self.rebuild(self.primitive, rasterizer, self.init.clone())
+    }
+
+    /// Rebuilds the pipeline state with a new pipeline init struct, ie with different blend
+    /// or depth state, on failure the previous pipeline state & init struct are kept
+    pub fn set_init(&mut self, init: I) -> Result<(), ShaderWatchError> {
+        self.rebuild(self.primitive, self.rasterizer, init)
+    }
+
+    fn rebuild(
+        &mut self,
+        primitive: Primitive,
+        rasterizer: state::Rasterizer,
+        init: I,
+    ) -> Result<(), ShaderWatchError> {
+        let (pso, program) = self.sources.create_pipeline(
+            &mut self.factory,
+            &self.cache,
+            primitive,
+            rasterizer,
+            init.clone(),
+        )?;
+        self.pso = pso;
+        self.program = program;
+        self.primitive = primitive;
+        self.rasterizer = rasterizer;
+        self.init = init;
+        self.generation += 1;
+        Ok(())
+    }
 }
 
 impl<R: Resources, F: Factory<R>, I: pso::PipelineInit> PsoCell<R, F, I>
@@ -31,13 +132,20 @@ impl<R: Resources, F: Factory<R>, I: pso::PipelineInit> PsoCell<R, F, I>
     fn factory(&mut self) -> &mut F {
         &mut self.factory
     }
+
+    fn generation(&self) -> u64 {
+        self.generation
+    }
 }
 
 /// Builds `SimplePsoCell`
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SimplePsoCellBuilder<I: pso::PipelineInit> {
-    vertex_shader: Option<Vec<u8>>,
-    fragment_shader: Option<Vec<u8>>,
+    shaders: Stages<Vec<u8>>,
+    shader: Option<Vec<u8>>,
+    include_sources: HashMap<PathBuf, Vec<u8>>,
+    defines: Vec<(String, String)>,
+    cache: Option<ShaderCache>,
     primitive: Primitive,
     rasterizer: state::Rasterizer,
     init: I,
@@ -46,8 +154,11 @@ pub struct SimplePsoCellBuilder<I: pso::PipelineInit> {
 impl<I: pso::PipelineInit + Clone> SimplePsoCellBuilder<I> {
     pub fn using(init_struct: I) -> SimplePsoCellBuilder<I> {
         SimplePsoCellBuilder {
-            vertex_shader: None,
-            fragment_shader: None,
+            shaders: Stages::default(),
+            shader: None,
+            include_sources: HashMap::new(),
+            defines: Vec::new(),
+            cache: None,
             init: init_struct,
             primitive: Primitive::TriangleList,
             rasterizer: state::Rasterizer::new_fill(),
@@ -55,12 +166,103 @@ impl<I: pso::PipelineInit + Clone> SimplePsoCellBuilder<I> {
     }
 
     pub fn vertex_shader(mut self, bytes: &[u8]) -> SimplePsoCellBuilder<I> {
-        self.vertex_shader = Some(bytes.into());
+        self.shaders.vertex = Some(bytes.into());
+        self
+    }
+
+    /// Optional tessellation control shader stage, requires a domain shader
+    pub fn hull_shader(mut self, bytes: &[u8]) -> SimplePsoCellBuilder<I> {
+        self.shaders.hull = Some(bytes.into());
+        self
+    }
+
+    /// Optional tessellation evaluation shader stage, requires a hull shader
+    pub fn domain_shader(mut self, bytes: &[u8]) -> SimplePsoCellBuilder<I> {
+        self.shaders.domain = Some(bytes.into());
+        self
+    }
+
+    /// Optional geometry shader stage
+    pub fn geometry_shader(mut self, bytes: &[u8]) -> SimplePsoCellBuilder<I> {
+        self.shaders.geometry = Some(bytes.into());
         self
     }
 
     pub fn fragment_shader(mut self, bytes: &[u8]) -> SimplePsoCellBuilder<I> {
-        self.fragment_shader = Some(bytes.into());
+        self.shaders.fragment = Some(bytes.into());
+        self
+    }
+
+    /// Single file shader with each stage's code following a `#pragma stage <name>` line,
+    /// ie `#pragma stage vertex` & `#pragma stage fragment`. Code above the first marker is
+    /// shared by every stage. Stages set individually take precedence over the file's
+    pub fn shader(mut self, bytes: &[u8]) -> SimplePsoCellBuilder<I> {
+        self.shader = Some(bytes.into());
+        self
+    }
+
+    /// Provides the source of a file that shaders `#include`, keyed by path relative to the root
+    /// shader, ie `"common/lighting.glsl"`. Includes are resolved relative to the including file
+    /// so `#include "noise.glsl"` within `common/lighting.glsl` is `"common/noise.glsl"`
+    pub fn include_source<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        bytes: &[u8],
+    ) -> SimplePsoCellBuilder<I> {
+        self.include_sources
+            .insert(normalize(path.as_ref()), bytes.into());
+        self
+    }
+
+    /// Defines a preprocessor macro in every shader stage, injected as `#define name value`
+    /// after the `#version` directive. An empty value defines the name alone, ie `"SHADOWS", ""`
+    pub fn define<N: Into<String>, V: Into<String>>(
+        mut self,
+        name: N,
+        value: V,
+    ) -> SimplePsoCellBuilder<I> {
+        let name = name.into();
+        self.defines.retain(|(n, _)| *n != name);
+        self.defines.push((name, value.into()));
+        self
+    }
+
+    /// Ignored as compile time shaders aren't watched, allows the `debug_watcher_pso_cell!`
+    /// macro to use a hub in debug mode
+    pub fn hub(self, _hub: &ShaderWatchHub) -> SimplePsoCellBuilder<I> {
+        self
+    }
+
+    /// Ignored as compile time shaders aren't watched, allows the `debug_watcher_pso_cell!`
+    /// macro to debounce rebuilds in debug mode
+    pub fn debounce(self, _debounce: Duration) -> SimplePsoCellBuilder<I> {
+        self
+    }
+
+    /// Ignored as compile time shaders aren't watched, allows the `debug_watcher_pso_cell!`
+    /// macro to choose the file watching implementation in debug mode
+    pub fn watcher_backend(self, _backend: WatcherBackend) -> SimplePsoCellBuilder<I> {
+        self
+    }
+
+    /// Ignored as compile time shaders aren't rebuilt, allows the `debug_watcher_pso_cell!`
+    /// macro to keep a rollback history in debug mode
+    pub fn history(self, _len: usize) -> SimplePsoCellBuilder<I> {
+        self
+    }
+
+    /// Ignored as compile time shaders aren't watched, allows the `debug_watcher_pso_cell!`
+    /// macro to use a pipeline config file in debug mode. Use `primitive` & `rasterizer`
+    /// for release mode settings
+    #[cfg(feature = "config-file")]
+    pub fn config_file<P: Into<PathBuf>>(self, _path: P) -> SimplePsoCellBuilder<I> {
+        self
+    }
+
+    /// Compiles shaders with a cache shared with other cells, so cells using the same
+    /// shader compile it once
+    pub fn shader_cache(mut self, cache: &ShaderCache) -> SimplePsoCellBuilder<I> {
+        self.cache = Some(cache.clone());
         self
     }
 
@@ -74,16 +276,62 @@ impl<I: pso::PipelineInit + Clone> SimplePsoCellBuilder<I> {
         self
     }
 
-    pub fn build<R, F>(self, mut factory: F) -> Result<SimplePsoCell<R, F, I>, Box<dyn Error>>
+    pub fn build<R, F>(self, mut factory: F) -> Result<SimplePsoCell<R, F, I>, ShaderWatchError>
     where
         R: Resources,
         F: Factory<R>,
     {
-        let vs = self.vertex_shader.ok_or("missing vertex shader")?;
-        let fs = self.fragment_shader.ok_or("missing fragment shader")?;
-        let set = factory.create_shader_set(&vs, &fs)?;
-        let pso =
-            factory.create_pipeline_state(&set, self.primitive, self.rasterizer, self.init)?;
-        Ok(SimplePsoCell { pso, factory })
+        let mut sources = self
+            .shaders
+            .try_map(|bytes| expand_includes(bytes, &self.include_sources))?;
+        if let Some(bytes) = &self.shader {
+            sources = sources.or(expand_includes(bytes, &self.include_sources)?.split_stages()?);
+        }
+        sources.for_each_mut(|source| source.define(&self.defines));
+        let cache = self.cache.unwrap_or_default();
+        let (pso, program) = sources.create_pipeline(
+            &mut factory,
+            &cache,
+            self.primitive,
+            self.rasterizer,
+            self.init.clone(),
+        )?;
+        Ok(SimplePsoCell {
+            pso,
+            factory,
+            _lease: cache.lease::<R>(&sources),
+            sources,
+            cache,
+            program,
+            primitive: self.primitive,
+            rasterizer: self.rasterizer,
+            init: self.init,
+            generation: 0,
+        })
+    }
+}
+
+impl<R, F, I> PermutationBuilder<R, F> for SimplePsoCellBuilder<I>
+where
+    R: Resources,
+    F: Factory<R>,
+    I: pso::PipelineInit + Clone,
+{
+    type Init = I;
+    type Cell = SimplePsoCell<R, F, I>;
+
+    fn share(mut self) -> Result<SimplePsoCellBuilder<I>, ShaderWatchError> {
+        self.cache.get_or_insert_with(ShaderCache::new);
+        Ok(self)
+    }
+
+    fn permutation(self, permutation: &Permutation) -> SimplePsoCellBuilder<I> {
+        permutation
+            .defines()
+            .fold(self, |builder, (name, value)| builder.define(name, value))
+    }
+
+    fn build_cell(self, factory: F) -> Result<SimplePsoCell<R, F, I>, ShaderWatchError> {
+        self.build(factory)
     }
 }
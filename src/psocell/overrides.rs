@@ -0,0 +1,64 @@
+use gfx::state;
+use log::info;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+static RASTERIZER_OVERRIDE: Mutex<Option<RasterizerOverride>> = Mutex::new(None);
+/// Incremented on each change to `RASTERIZER_OVERRIDE`
+static OVERRIDE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Process wide rasterizer setting applied to every `WatcherPsoCell`, see
+/// `set_rasterizer_override`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RasterizerOverride {
+    /// Each cell's own rasterizer drawing polygon outlines
+    Wireframe,
+    /// Replaces each cell's rasterizer
+    Rasterizer(state::Rasterizer),
+}
+
+/// Overrides the rasterizer of every `WatcherPsoCell`, ie to toggle wireframe from a debug menu.
+/// `None` restores each cell's own rasterizer.
+///
+/// Cells relink their active shaders on their next `pso()` call, without reading the shader
+/// files, & history entries when activated by `rollback()` or `roll_forward()`. Compile time
+/// `SimplePsoCell`s are unaffected.
+pub fn set_rasterizer_override(rasterizer_override: Option<RasterizerOverride>) {
+    let mut current = RASTERIZER_OVERRIDE
+        .lock()
+        .unwrap_or_else(|poison| poison.into_inner());
+    if *current != rasterizer_override {
+        match rasterizer_override {
+            Some(o) => info!("Overriding rasterizer {o:?}"),
+            None => info!("Restoring rasterizers"),
+        }
+        *current = rasterizer_override;
+        OVERRIDE_GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the current process wide rasterizer override
+pub fn rasterizer_override() -> Option<RasterizerOverride> {
+    *RASTERIZER_OVERRIDE
+        .lock()
+        .unwrap_or_else(|poison| poison.into_inner())
+}
+
+/// Returns a number that changes whenever the rasterizer override does
+pub(crate) fn override_generation() -> u64 {
+    OVERRIDE_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Applies the rasterizer override, if any, to a cell's rasterizer
+pub(crate) fn apply_override(rasterizer: state::Rasterizer) -> state::Rasterizer {
+    match rasterizer_override() {
+        Some(RasterizerOverride::Wireframe) => state::Rasterizer {
+            method: state::RasterMethod::Line(1),
+            ..rasterizer
+        },
+        Some(RasterizerOverride::Rasterizer(rasterizer)) => rasterizer,
+        None => rasterizer,
+    }
+}
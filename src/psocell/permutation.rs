@@ -0,0 +1,261 @@
+use super::{PsoCell, ShaderWatchError};
+use gfx::*;
+use log::debug;
+use std::collections::{BTreeMap, HashMap};
+
+/// Builds the cells of a `PsoPermutationCell`, implemented by `WatcherPsoCellBuilder`
+/// & `SimplePsoCellBuilder`
+pub trait PermutationBuilder<R: Resources, F: Factory<R>>: Clone {
+    type Init: pso::PipelineInit;
+    type Cell: PsoCell<R, F, Self::Init>;
+
+    /// Prepares the builder to build a cell per permutation,
+    /// ie sharing a single file watcher & shader cache between them
+    fn share(self) -> Result<Self, ShaderWatchError>;
+
+    /// Defines the permutation's keys in every shader stage
+    fn permutation(self, permutation: &Permutation) -> Self;
+
+    fn build_cell(self, factory: F) -> Result<Self::Cell, ShaderWatchError>;
+}
+
+/// Combination of `PsoPermutationCell` keys, each injected into the shaders as a `#define`
+///
+/// ```ignore
+/// let shadowed = Permutation::new().flag("SHADOWS", true).key("MAX_LIGHTS", "8");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Permutation {
+    defines: BTreeMap<String, String>,
+}
+
+impl Permutation {
+    pub fn new() -> Permutation {
+        Permutation::default()
+    }
+
+    /// Sets a boolean key, defined without a value when enabled, ie `#define SHADOWS`
+    pub fn flag<N: Into<String>>(mut self, name: N, enabled: bool) -> Permutation {
+        let name = name.into();
+        match enabled {
+            true => self.defines.insert(name, String::new()),
+            false => self.defines.remove(&name),
+        };
+        self
+    }
+
+    /// Sets an enumerated key, ie `#define MAX_LIGHTS 8`
+    pub fn key<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Permutation {
+        self.defines.insert(name.into(), value.into());
+        self
+    }
+
+    /// Returns each key's name & value, the value is empty for flags
+    pub fn defines(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.defines
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// Container of pipeline state objects built from the same shaders with different sets of
+/// `#define`s, ie with & without `SHADOWS`. Each permutation is built on first use.
+///
+/// Built from a `WatcherPsoCellBuilder`, every live permutation is rebuilt when the shader
+/// files change using a single file watcher. Built from a `SimplePsoCellBuilder` the compile
+/// time shader source is embedded once for all permutations. See `debug_pso_permutation_cell!`.
+///
+/// ```ignore
+/// let mut cell = PsoPermutationCell::new(builder, factory)?
+///     .flag("SHADOWS")
+///     .key("MAX_LIGHTS", ["1", "4", "8"]);
+/// let shadowed = Permutation::new().flag("SHADOWS", true).key("MAX_LIGHTS", "8");
+/// encoder.draw(&slice, cell.pso(&shadowed)?, &data);
+/// ```
+pub struct PsoPermutationCell<R: Resources, F: Factory<R>, B: PermutationBuilder<R, F>> {
+    builder: B,
+    factory: F,
+    flags: Vec<String>,
+    keys: Vec<(String, Vec<String>)>,
+    cells: HashMap<Permutation, B::Cell>,
+    /// Permutation rebuilt by the last `pso()` call, the others are polled by the next
+    rebuilt: Option<Permutation>,
+}
+
+impl<R, F, B> PsoPermutationCell<R, F, B>
+where
+    R: Resources,
+    F: Factory<R> + Clone,
+    B: PermutationBuilder<R, F>,
+{
+    pub fn new(builder: B, factory: F) -> Result<PsoPermutationCell<R, F, B>, ShaderWatchError> {
+        Ok(PsoPermutationCell {
+            builder: builder.share()?,
+            factory,
+            flags: Vec::new(),
+            keys: Vec::new(),
+            cells: HashMap::new(),
+            rebuilt: None,
+        })
+    }
+
+    /// Declares a boolean key
+    pub fn flag<N: Into<String>>(mut self, name: N) -> PsoPermutationCell<R, F, B> {
+        self.flags.push(name.into());
+        self
+    }
+
+    /// Declares an enumerated key & its possible values
+    pub fn key<N, V, I>(mut self, name: N, values: I) -> PsoPermutationCell<R, F, B>
+    where
+        N: Into<String>,
+        V: Into<String>,
+        I: IntoIterator<Item = V>,
+    {
+        let values = values.into_iter().map(Into::into).collect();
+        self.keys.push((name.into(), values));
+        self
+    }
+
+    /// Returns the pipeline state of the permutation, building it if this is its first use.
+    /// Errors if the permutation uses undeclared keys or values, or fails to build.
+    ///
+    /// When a permutation is rebuilt after a shader file change, every other live
+    /// permutation is rebuilt by the next call
+    pub fn pso(
+        &mut self,
+        permutation: &Permutation,
+    ) -> Result<&mut PipelineState<R, <B::Init as pso::PipelineInit>::Meta>, ShaderWatchError> {
+        if !self.cells.contains_key(permutation) {
+            check(permutation, &self.flags, &self.keys)?;
+            debug!("Building permutation {permutation:?}");
+            let cell = self
+                .builder
+                .clone()
+                .permutation(permutation)
+                .build_cell(self.factory.clone())?;
+            self.cells.insert(permutation.clone(), cell);
+        }
+
+        // the returned pipeline state borrows `cells`, so the other cells are polled next call
+        if let Some(rebuilt) = self.rebuilt.take() {
+            for (other, cell) in &mut self.cells {
+                if *other != rebuilt {
+                    cell.pso_with_status();
+                }
+            }
+        }
+        let (pso, rebuilt) = self
+            .cells
+            .get_mut(permutation)
+            .expect("permutation cell built")
+            .pso_with_status();
+        if rebuilt {
+            self.rebuilt = Some(permutation.clone());
+        }
+        Ok(pso)
+    }
+
+    /// Returns the permutation's cell if it has been built, ie to query reload status
+    pub fn cell_mut(&mut self, permutation: &Permutation) -> Option<&mut B::Cell> {
+        self.cells.get_mut(permutation)
+    }
+
+    /// Drops the permutation's pipeline state, returns `false` if it wasn't built
+    pub fn remove(&mut self, permutation: &Permutation) -> bool {
+        self.cells.remove(permutation).is_some()
+    }
+
+    /// Returns the permutations built & not removed
+    pub fn permutations(&self) -> impl Iterator<Item = &Permutation> {
+        self.cells.keys()
+    }
+
+    pub fn factory(&mut self) -> &mut F {
+        &mut self.factory
+    }
+}
+
+/// Errors if the permutation uses a key or value not declared
+fn check(
+    permutation: &Permutation,
+    flags: &[String],
+    keys: &[(String, Vec<String>)],
+) -> Result<(), ShaderWatchError> {
+    for (name, value) in permutation.defines() {
+        let valid = match keys.iter().find(|(key, _)| key == name) {
+            Some((_, values)) => values.iter().any(|v| v == value),
+            None if flags.iter().any(|flag| flag == name) => value.is_empty(),
+            None => {
+                return Err(ShaderWatchError::UnknownPermutation {
+                    name: name.into(),
+                    value: None,
+                })
+            }
+        };
+        if !valid {
+            return Err(ShaderWatchError::UnknownPermutation {
+                name: name.into(),
+                value: Some(value.into()),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn declared() -> (Vec<String>, Vec<(String, Vec<String>)>) {
+        let flags = vec!["SHADOWS".to_string()];
+        let keys = vec![("MAX_LIGHTS".to_string(), vec!["1".into(), "8".into()])];
+        (flags, keys)
+    }
+
+    #[test]
+    fn check_declared() {
+        let (flags, keys) = declared();
+        let permutation = Permutation::new()
+            .flag("SHADOWS", true)
+            .key("MAX_LIGHTS", "8");
+        assert!(check(&permutation, &flags, &keys).is_ok());
+        assert!(check(&Permutation::new(), &flags, &keys).is_ok());
+        // a disabled flag isn't defined
+        let disabled = Permutation::new().flag("FOG", true).flag("FOG", false);
+        assert!(check(&disabled, &flags, &keys).is_ok());
+    }
+
+    #[test]
+    fn check_unknown_key() {
+        let (flags, keys) = declared();
+        let err = check(&Permutation::new().flag("FOG", true), &flags, &keys).unwrap_err();
+        assert!(matches!(
+            &err,
+            ShaderWatchError::UnknownPermutation { name, value: None } if name == "FOG"
+        ));
+        assert_eq!(err.to_string(), "unknown permutation key `FOG`");
+    }
+
+    #[test]
+    fn check_unknown_value() {
+        let (flags, keys) = declared();
+        let err = check(&Permutation::new().key("MAX_LIGHTS", "4"), &flags, &keys).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permutation key `MAX_LIGHTS` has no value `4`"
+        );
+
+        let err = check(&Permutation::new().flag("MAX_LIGHTS", true), &flags, &keys).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permutation key `MAX_LIGHTS` is not a flag"
+        );
+
+        let err = check(&Permutation::new().key("SHADOWS", "1"), &flags, &keys).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "permutation key `SHADOWS` has no value `1`"
+        );
+    }
+}
@@ -0,0 +1,19 @@
+use log::info;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Incremented by each `mark_all_dirty` call
+static DIRTY_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Marks every `WatcherPsoCell` to be rebuilt from its shader files on its next `pso()` call,
+/// regardless of file changes, ie for a debug console "reload shaders" command.
+/// See `WatcherPsoCell::mark_dirty` to rebuild a single cell. Compile time `SimplePsoCell`s
+/// are unaffected.
+pub fn mark_all_dirty() {
+    info!("Marking all shaders dirty");
+    DIRTY_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns a number that changes whenever `mark_all_dirty` is called
+pub(crate) fn dirty_generation() -> u64 {
+    DIRTY_GENERATION.load(Ordering::Relaxed)
+}
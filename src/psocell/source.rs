@@ -0,0 +1,573 @@
+use super::{error::copy_io_error, stages::Stages, ShaderStage, ShaderWatchError};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+/// Error resolving an `#include` directive
+#[derive(Debug)]
+pub enum IncludeError {
+    /// An included file could not be read
+    NotFound {
+        path: PathBuf,
+        included_from: PathBuf,
+        line: usize,
+        error: io::Error,
+    },
+    /// A file includes itself, directly or via other includes.
+    /// Contains the include chain ending with the repeated file
+    Cycle(Vec<PathBuf>),
+}
+
+impl fmt::Display for IncludeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IncludeError::NotFound {
+                path,
+                included_from,
+                line,
+                error,
+            } => {
+                if included_from.as_os_str().is_empty() {
+                    write!(f, "line {line}: ")?;
+                } else {
+                    write!(f, "{}:{line}: ", included_from.display())?;
+                }
+                write!(f, "cannot include {}: {error}", path.display())
+            }
+            IncludeError::Cycle(chain) => {
+                write!(f, "include cycle: ")?;
+                // compile time shader bytes have an empty root path
+                let chain = chain.iter().filter(|p| !p.as_os_str().is_empty());
+                for (idx, path) in chain.enumerate() {
+                    if idx > 0 {
+                        write!(f, " -> ")?;
+                    }
+                    write!(f, "{}", path.display())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Io errors, which aren't `Clone`, are copied by kind & message
+impl Clone for IncludeError {
+    fn clone(&self) -> Self {
+        match self {
+            IncludeError::NotFound {
+                path,
+                included_from,
+                line,
+                error,
+            } => IncludeError::NotFound {
+                path: path.clone(),
+                included_from: included_from.clone(),
+                line: *line,
+                error: copy_io_error(error),
+            },
+            IncludeError::Cycle(chain) => IncludeError::Cycle(chain.clone()),
+        }
+    }
+}
+
+impl Error for IncludeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IncludeError::NotFound { error, .. } => Some(error),
+            IncludeError::Cycle(_) => None,
+        }
+    }
+}
+
+/// Shader code with `#include` directives expanded
+#[derive(Debug, Clone)]
+pub(crate) struct ShaderSource {
+    pub(crate) code: Vec<u8>,
+    /// Files the code originates from, an empty path for compile time shader bytes
+    files: Vec<PathBuf>,
+    /// File index & 1-based line each line of `code` originates from
+    lines: Vec<(usize, usize)>,
+    /// Content of each file read from disk, before expansion
+    pub(crate) originals: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl ShaderSource {
+    fn new(path: &Path) -> ShaderSource {
+        ShaderSource {
+            code: Vec::new(),
+            files: vec![path.to_path_buf()],
+            lines: Vec::new(),
+            originals: Vec::new(),
+        }
+    }
+
+    /// Returns the root shader file, `None` for compile time shader bytes
+    pub(crate) fn path(&self) -> Option<&Path> {
+        Some(self.files[0].as_path()).filter(|p| !p.as_os_str().is_empty())
+    }
+
+    /// Returns a hash of the expanded code
+    pub(crate) fn code_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.code.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the file & line that 1-based `line` of the expanded code originates from,
+    /// the path is `None` for compile time shader bytes
+    pub(crate) fn origin(&self, line: usize) -> Option<(Option<&Path>, usize)> {
+        let (file, line) = *self.lines.get(line.checked_sub(1)?)?;
+        let path = Some(self.files[file].as_path()).filter(|p| !p.as_os_str().is_empty());
+        Some((path, line))
+    }
+
+    /// Injects `#define name value` lines after the `#version` directive, or at the top
+    /// if there is none. Injected lines originate from the `#version` line
+    pub(crate) fn define(&mut self, defines: &[(String, String)]) {
+        if defines.is_empty() {
+            return;
+        }
+        let mut offset = 0;
+        let mut insert_at = None;
+        for (idx, line) in self.code.split_inclusive(|b| *b == b'\n').enumerate() {
+            offset += line.len();
+            if is_version(line) {
+                insert_at = Some((idx + 1, offset));
+                break;
+            }
+        }
+        let (line_idx, offset) = insert_at.unwrap_or((0, 0));
+        let origin = match line_idx {
+            0 => (0, 1),
+            _ => self.lines[line_idx - 1],
+        };
+
+        let injected: String = defines
+            .iter()
+            .map(|(name, value)| match value.is_empty() {
+                true => format!("#define {name}\n"),
+                false => format!("#define {name} {value}\n"),
+            })
+            .collect();
+        self.code.splice(offset..offset, injected.into_bytes());
+        self.lines
+            .splice(line_idx..line_idx, defines.iter().map(|_| origin));
+    }
+
+    /// Splits single file shader code into the stages marked by `#pragma stage <name>` lines,
+    /// code above the first marker is shared by every stage. Each stage's code has blank
+    /// lines in place of the markers & other stages' code so line numbers are unchanged
+    pub(crate) fn split_stages(&self) -> Result<Stages<ShaderSource>, ShaderWatchError> {
+        let mut kinds = Vec::new();
+        let mut current = LineKind::Shared;
+        for (idx, line) in self.code.split_inclusive(|b| *b == b'\n').enumerate() {
+            let Some(name) = stage_marker(line) else {
+                kinds.push(current);
+                continue;
+            };
+            let stage = ShaderStage::from_name(name).ok_or_else(|| {
+                let (path, line) = self.origin(idx + 1).unwrap_or((None, idx + 1));
+                ShaderWatchError::UnknownStage {
+                    path: path.map(PathBuf::from),
+                    line,
+                    name: name.into(),
+                }
+            })?;
+            current = LineKind::Stage(stage);
+            kinds.push(LineKind::Marker);
+        }
+        if !kinds.iter().any(|kind| matches!(kind, LineKind::Marker)) {
+            return Err(ShaderWatchError::NoStageMarkers {
+                path: self.path().map(PathBuf::from),
+            });
+        }
+
+        let mut stages = Stages::default();
+        for kind in &kinds {
+            let LineKind::Stage(stage) = *kind else {
+                continue;
+            };
+            if stages.stage_mut(stage).is_some() {
+                continue;
+            }
+            let mut code = Vec::with_capacity(self.code.len());
+            let lines = self.code.split_inclusive(|b| *b == b'\n');
+            for (line, kind) in lines.zip(&kinds) {
+                match kind {
+                    LineKind::Shared => code.extend_from_slice(line),
+                    LineKind::Stage(s) if *s == stage => code.extend_from_slice(line),
+                    _ => code.push(b'\n'),
+                }
+            }
+            *stages.stage_mut(stage) = Some(ShaderSource {
+                code,
+                files: self.files.clone(),
+                lines: self.lines.clone(),
+                originals: self.originals.clone(),
+            });
+        }
+        Ok(stages)
+    }
+
+    fn push_line(&mut self, path: &Path, idx: usize, line: &[u8]) {
+        let file = match self.files.iter().position(|f| f == path) {
+            Some(file) => file,
+            None => {
+                self.files.push(path.to_path_buf());
+                self.files.len() - 1
+            }
+        };
+        self.code.extend_from_slice(line);
+        if !line.ends_with(b"\n") {
+            self.code.push(b'\n');
+        }
+        self.lines.push((file, idx + 1));
+    }
+}
+
+/// Line of a single file shader, see `ShaderSource::split_stages`
+#[derive(Debug, Clone, Copy)]
+enum LineKind {
+    /// Code above the first stage marker
+    Shared,
+    /// `#pragma stage <name>` line
+    Marker,
+    Stage(ShaderStage),
+}
+
+/// Reads a shader file expanding `#include "path"` directives, paths are relative to the
+/// including file. Every file read, or attempted to be read, is added to `files`
+/// so it can be watched even if loading fails
+pub(crate) fn load_shader(
+    path: &Path,
+    files: &mut Vec<PathBuf>,
+) -> Result<ShaderSource, ShaderWatchError> {
+    let path = normalize(path);
+    add_file(files, &path);
+    let code = fs::read(&path).map_err(|error| ShaderWatchError::Io {
+        path: path.clone(),
+        error,
+    })?;
+
+    let mut source = ShaderSource::new(&path);
+    let mut originals = vec![(path.clone(), code.clone())];
+    expand(
+        &path,
+        &code,
+        &mut Vec::new(),
+        files,
+        &mut source,
+        &mut |p| {
+            let code = fs::read(p)?;
+            originals.push((p.to_path_buf(), code.clone()));
+            Ok(code)
+        },
+    )?;
+    source.originals = originals;
+    Ok(source)
+}
+
+/// Expands `#include` directives in compile time shader bytes using the provided
+/// include sources, keyed by path relative to the root shader
+pub(crate) fn expand_includes(
+    code: &[u8],
+    sources: &HashMap<PathBuf, Vec<u8>>,
+) -> Result<ShaderSource, IncludeError> {
+    let mut source = ShaderSource::new(Path::new(""));
+    expand(
+        Path::new(""),
+        code,
+        &mut Vec::new(),
+        &mut Vec::new(),
+        &mut source,
+        &mut |p| {
+            sources.get(p).cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "include source not provided")
+            })
+        },
+    )?;
+    Ok(source)
+}
+
+fn expand(
+    path: &Path,
+    code: &[u8],
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<PathBuf>,
+    out: &mut ShaderSource,
+    read: &mut impl FnMut(&Path) -> io::Result<Vec<u8>>,
+) -> Result<(), IncludeError> {
+    stack.push(path.to_path_buf());
+    for (idx, line) in code.split_inclusive(|b| *b == b'\n').enumerate() {
+        let Some(target) = include_target(line) else {
+            out.push_line(path, idx, line);
+            continue;
+        };
+
+        let include = normalize(&path.parent().unwrap_or(path).join(target));
+        if stack.contains(&include) {
+            let mut chain = stack.clone();
+            chain.push(include);
+            return Err(IncludeError::Cycle(chain));
+        }
+        add_file(files, &include);
+
+        let included = read(&include).map_err(|error| IncludeError::NotFound {
+            path: include.clone(),
+            included_from: path.to_path_buf(),
+            line: idx + 1,
+            error,
+        })?;
+        expand(&include, &included, stack, files, out, read)?;
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// Returns the quoted path of an `#include "path"` or `#include <path>` line
+fn include_target(line: &[u8]) -> Option<&str> {
+    let directive = std::str::from_utf8(line).ok()?.trim().strip_prefix('#')?;
+    let arg = directive.trim_start().strip_prefix("include")?.trim();
+    arg.strip_prefix('"')
+        .and_then(|a| a.strip_suffix('"'))
+        .or_else(|| arg.strip_prefix('<').and_then(|a| a.strip_suffix('>')))
+        .filter(|target| !target.is_empty())
+}
+
+/// Returns the stage name of a `#pragma stage <name>` line, ignoring a trailing `//` comment
+fn stage_marker(line: &[u8]) -> Option<&str> {
+    let line = std::str::from_utf8(line).ok()?;
+    let code = line.split_once("//").map_or(line, |(code, _)| code);
+    let directive = code.trim().strip_prefix('#')?;
+    let mut words = directive
+        .trim_start()
+        .strip_prefix("pragma")?
+        .split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("stage"), Some(name), None) => Some(name),
+        _ => None,
+    }
+}
+
+fn is_version(line: &[u8]) -> bool {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.trim().strip_prefix('#'))
+        .is_some_and(|directive| directive.trim_start().starts_with("version"))
+}
+
+fn add_file(files: &mut Vec<PathBuf>, path: &Path) {
+    if !files.iter().any(|f| f == path) {
+        files.push(path.to_path_buf());
+    }
+}
+
+/// Lexically removes `.` & `..` components so the same file included via different
+/// relative paths is recognised as such
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(normal.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                normal.pop();
+            }
+            c => normal.push(c),
+        }
+    }
+    normal
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sources(files: &[(&str, &str)]) -> HashMap<PathBuf, Vec<u8>> {
+        files
+            .iter()
+            .map(|(path, code)| (PathBuf::from(path), code.as_bytes().to_vec()))
+            .collect()
+    }
+
+    fn code(source: &ShaderSource) -> &str {
+        std::str::from_utf8(&source.code).unwrap()
+    }
+
+    #[test]
+    fn expand_nested_includes() {
+        let includes = sources(&[
+            ("lib/light.glsl", "#include \"util.glsl\"\nfloat light;\n"),
+            ("lib/util.glsl", "float util;"),
+        ]);
+        let source = expand_includes(
+            b"#version 150\n  #include <lib/light.glsl>\nvoid main() {}\n",
+            &includes,
+        )
+        .unwrap();
+
+        assert_eq!(
+            code(&source),
+            "#version 150\nfloat util;\nfloat light;\nvoid main() {}\n"
+        );
+        assert_eq!(source.origin(1), Some((None, 1)));
+        assert_eq!(source.origin(2), Some((Some("lib/util.glsl".as_ref()), 1)));
+        assert_eq!(source.origin(3), Some((Some("lib/light.glsl".as_ref()), 2)));
+        assert_eq!(source.origin(4), Some((None, 3)));
+        assert_eq!(source.origin(5), None);
+    }
+
+    #[test]
+    fn include_relative_to_including_file() {
+        let includes = sources(&[
+            ("a/one.glsl", "#include \"../b/two.glsl\"\n"),
+            ("b/two.glsl", "float two;\n"),
+        ]);
+        let source = expand_includes(b"#include \"a/one.glsl\"\n", &includes).unwrap();
+        assert_eq!(code(&source), "float two;\n");
+    }
+
+    #[test]
+    fn include_not_found() {
+        let includes = sources(&[("a.glsl", "\n#include \"b.glsl\"\n")]);
+        let err = expand_includes(b"#include \"a.glsl\"\n", &includes).unwrap_err();
+        let IncludeError::NotFound {
+            path,
+            included_from,
+            line,
+            ..
+        } = &err
+        else {
+            panic!("expected NotFound, got {err:?}");
+        };
+        assert_eq!(path, Path::new("b.glsl"));
+        assert_eq!(included_from, Path::new("a.glsl"));
+        assert_eq!(*line, 2);
+    }
+
+    #[test]
+    fn include_cycle() {
+        let includes = sources(&[
+            ("a.glsl", "#include \"b.glsl\"\n"),
+            ("b.glsl", "#include \"./a.glsl\"\n"),
+        ]);
+        let err = expand_includes(b"#include \"a.glsl\"\n", &includes).unwrap_err();
+        let IncludeError::Cycle(chain) = &err else {
+            panic!("expected Cycle, got {err:?}");
+        };
+        assert_eq!(chain.len(), 4);
+        assert_eq!(err.to_string(), "include cycle: a.glsl -> b.glsl -> a.glsl");
+    }
+
+    #[test]
+    fn self_include_cycle() {
+        let includes = sources(&[("a.glsl", "#include \"a.glsl\"\n")]);
+        let err = expand_includes(b"#include \"a.glsl\"\n", &includes).unwrap_err();
+        assert_eq!(err.to_string(), "include cycle: a.glsl -> a.glsl");
+    }
+
+    #[test]
+    fn define_after_version() {
+        let mut source = expand_includes(
+            b"// header\n#version 150\nvoid main() {}\n",
+            &HashMap::new(),
+        )
+        .unwrap();
+        source.define(&[
+            ("SHADOWS".into(), String::new()),
+            ("LIGHTS".into(), "8".into()),
+        ]);
+
+        assert_eq!(
+            code(&source),
+            "// header\n#version 150\n#define SHADOWS\n#define LIGHTS 8\nvoid main() {}\n"
+        );
+        // injected lines originate from the `#version` line, following lines are unchanged
+        assert_eq!(source.origin(3), Some((None, 2)));
+        assert_eq!(source.origin(4), Some((None, 2)));
+        assert_eq!(source.origin(5), Some((None, 3)));
+    }
+
+    #[test]
+    fn define_without_version() {
+        let mut source = expand_includes(b"void main() {}\n", &HashMap::new()).unwrap();
+        source.define(&[("LIGHTS".into(), "4".into())]);
+
+        assert_eq!(code(&source), "#define LIGHTS 4\nvoid main() {}\n");
+        assert_eq!(source.origin(1), Some((None, 1)));
+        assert_eq!(source.origin(2), Some((None, 1)));
+    }
+
+    #[test]
+    fn split_stages_keeps_line_numbers() {
+        let source = expand_includes(
+            b"#version 150\n\
+              #pragma stage vertex\n\
+              void main() { gl_Position = vec4(0); }\n\
+              #pragma stage fragment // comment\n\
+              void main() {}\n",
+            &HashMap::new(),
+        )
+        .unwrap();
+        let stages = source.split_stages().unwrap();
+
+        let vertex = stages.vertex.as_ref().unwrap();
+        let fragment = stages.fragment.as_ref().unwrap();
+        assert!(stages.geometry.is_none());
+        assert_eq!(
+            code(vertex),
+            "#version 150\n\nvoid main() { gl_Position = vec4(0); }\n\n\n"
+        );
+        assert_eq!(code(fragment), "#version 150\n\n\n\nvoid main() {}\n");
+        assert_eq!(fragment.origin(5), Some((None, 5)));
+    }
+
+    #[test]
+    fn split_stages_unknown_stage() {
+        let source = expand_includes(
+            b"#pragma stage vertex\n\n#pragma stage pixel\n",
+            &HashMap::new(),
+        )
+        .unwrap();
+        match source.split_stages() {
+            Err(ShaderWatchError::UnknownStage { path, line, name }) => {
+                assert_eq!(path, None);
+                assert_eq!(line, 3);
+                assert_eq!(name, "pixel");
+            }
+            other => panic!("expected UnknownStage, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn split_stages_without_markers() {
+        let source = expand_includes(b"void main() {}\n", &HashMap::new()).unwrap();
+        assert!(matches!(
+            source.split_stages(),
+            Err(ShaderWatchError::NoStageMarkers { path: None })
+        ));
+    }
+
+    #[test]
+    fn stage_markers() {
+        assert_eq!(stage_marker(b"#pragma stage vertex\n"), Some("vertex"));
+        assert_eq!(
+            stage_marker(b"  # pragma  stage fragment\r\n"),
+            Some("fragment")
+        );
+        assert_eq!(
+            stage_marker(b"#pragma stage vertex // lighting\n"),
+            Some("vertex")
+        );
+        assert_eq!(stage_marker(b"#pragma stage\n"), None);
+        assert_eq!(stage_marker(b"#pragma stage vertex fragment\n"), None);
+        assert_eq!(stage_marker(b"// #pragma stage vertex\n"), None);
+        assert_eq!(stage_marker(b"#pragma optimize(off)\n"), None);
+    }
+}
@@ -0,0 +1,288 @@
+use super::{
+    cache::ShaderCache, diagnostic::parse_log, interface::describe_mismatch, source::ShaderSource,
+    ShaderWatchError,
+};
+use gfx::{shade::core::CreateShaderError, traits::FactoryExt, *};
+use std::{fmt, path::Path};
+
+/// Programmable pipeline stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShaderStage {
+    Vertex,
+    /// Tessellation control
+    Hull,
+    /// Tessellation evaluation
+    Domain,
+    Geometry,
+    Fragment,
+}
+
+impl ShaderStage {
+    /// Parses the stage name, as displayed, ie `"vertex"`
+    pub(crate) fn from_name(name: &str) -> Option<ShaderStage> {
+        match name {
+            "vertex" => Some(ShaderStage::Vertex),
+            "hull" => Some(ShaderStage::Hull),
+            "domain" => Some(ShaderStage::Domain),
+            "geometry" => Some(ShaderStage::Geometry),
+            "fragment" => Some(ShaderStage::Fragment),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ShaderStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ShaderStage::Vertex => "vertex",
+            ShaderStage::Hull => "hull",
+            ShaderStage::Domain => "domain",
+            ShaderStage::Geometry => "geometry",
+            ShaderStage::Fragment => "fragment",
+        })
+    }
+}
+
+/// Shader of each pipeline stage, either file paths or source bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Stages<T> {
+    pub(crate) vertex: Option<T>,
+    pub(crate) hull: Option<T>,
+    pub(crate) domain: Option<T>,
+    pub(crate) geometry: Option<T>,
+    pub(crate) fragment: Option<T>,
+}
+
+impl<T> Default for Stages<T> {
+    fn default() -> Self {
+        Stages {
+            vertex: None,
+            hull: None,
+            domain: None,
+            geometry: None,
+            fragment: None,
+        }
+    }
+}
+
+impl<T> Stages<T> {
+    /// Errors if a required stage is missing
+    pub(crate) fn check(&self) -> Result<(), ShaderWatchError> {
+        if self.vertex.is_none() {
+            return Err(ShaderWatchError::MissingStage(ShaderStage::Vertex));
+        }
+        if self.fragment.is_none() {
+            return Err(ShaderWatchError::MissingStage(ShaderStage::Fragment));
+        }
+        match (&self.hull, &self.domain) {
+            (Some(_), None) => Err(ShaderWatchError::MissingStage(ShaderStage::Domain)),
+            (None, Some(_)) => Err(ShaderWatchError::MissingStage(ShaderStage::Hull)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn stage_mut(&mut self, stage: ShaderStage) -> &mut Option<T> {
+        match stage {
+            ShaderStage::Vertex => &mut self.vertex,
+            ShaderStage::Hull => &mut self.hull,
+            ShaderStage::Domain => &mut self.domain,
+            ShaderStage::Geometry => &mut self.geometry,
+            ShaderStage::Fragment => &mut self.fragment,
+        }
+    }
+
+    /// Sets each stage not already present from `other`
+    pub(crate) fn or(self, other: Stages<T>) -> Stages<T> {
+        Stages {
+            vertex: self.vertex.or(other.vertex),
+            hull: self.hull.or(other.hull),
+            domain: self.domain.or(other.domain),
+            geometry: self.geometry.or(other.geometry),
+            fragment: self.fragment.or(other.fragment),
+        }
+    }
+
+    pub(crate) fn for_each_mut<M: FnMut(&mut T)>(&mut self, mut f: M) {
+        for stage in [
+            &mut self.vertex,
+            &mut self.hull,
+            &mut self.domain,
+            &mut self.geometry,
+            &mut self.fragment,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            f(stage);
+        }
+    }
+
+    /// Returns each present stage, in pipeline order
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        [
+            &self.vertex,
+            &self.hull,
+            &self.domain,
+            &self.geometry,
+            &self.fragment,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Maps each present stage
+    pub(crate) fn map<U, M: FnMut(&T) -> U>(&self, mut map: M) -> Stages<U> {
+        Stages {
+            vertex: self.vertex.as_ref().map(&mut map),
+            hull: self.hull.as_ref().map(&mut map),
+            domain: self.domain.as_ref().map(&mut map),
+            geometry: self.geometry.as_ref().map(&mut map),
+            fragment: self.fragment.as_ref().map(&mut map),
+        }
+    }
+
+    /// Maps each present stage, in pipeline order
+    pub(crate) fn try_map<U, E, M>(&self, mut map: M) -> Result<Stages<U>, E>
+    where
+        M: FnMut(&T) -> Result<U, E>,
+    {
+        Ok(Stages {
+            vertex: self.vertex.as_ref().map(&mut map).transpose()?,
+            hull: self.hull.as_ref().map(&mut map).transpose()?,
+            domain: self.domain.as_ref().map(&mut map).transpose()?,
+            geometry: self.geometry.as_ref().map(&mut map).transpose()?,
+            fragment: self.fragment.as_ref().map(&mut map).transpose()?,
+        })
+    }
+}
+
+impl Stages<ShaderSource> {
+    /// Compiles each stage, reusing shaders in the cache, & creates the shader set
+    pub(crate) fn create_shader_set<R, F>(
+        &self,
+        factory: &mut F,
+        cache: &ShaderCache,
+    ) -> Result<ShaderSet<R>, ShaderWatchError>
+    where
+        R: Resources,
+        F: Factory<R>,
+    {
+        self.check()?;
+        let vertex = self
+            .vertex
+            .as_ref()
+            .ok_or(ShaderWatchError::MissingStage(ShaderStage::Vertex))?;
+        let fragment = self
+            .fragment
+            .as_ref()
+            .ok_or(ShaderWatchError::MissingStage(ShaderStage::Fragment))?;
+
+        let vs = cache.get_or_compile(vertex, |source| {
+            compile(ShaderStage::Vertex, source, |code| {
+                factory.create_shader_vertex(code)
+            })
+        })?;
+        let hs = self
+            .hull
+            .as_ref()
+            .map(|hull| {
+                cache.get_or_compile(hull, |source| {
+                    compile(ShaderStage::Hull, source, |code| {
+                        factory.create_shader_hull(code)
+                    })
+                })
+            })
+            .transpose()?;
+        let ds = self
+            .domain
+            .as_ref()
+            .map(|domain| {
+                cache.get_or_compile(domain, |source| {
+                    compile(ShaderStage::Domain, source, |code| {
+                        factory.create_shader_domain(code)
+                    })
+                })
+            })
+            .transpose()?;
+        let gs = self
+            .geometry
+            .as_ref()
+            .map(|geometry| {
+                cache.get_or_compile(geometry, |source| {
+                    compile(ShaderStage::Geometry, source, |code| {
+                        factory.create_shader_geometry(code)
+                    })
+                })
+            })
+            .transpose()?;
+        let ps = cache.get_or_compile(fragment, |source| {
+            compile(ShaderStage::Fragment, source, |code| {
+                factory.create_shader_pixel(code)
+            })
+        })?;
+
+        Ok(match (hs, ds, gs) {
+            (Some(hs), Some(ds), Some(gs)) => ShaderSet::TessellatedGeometry(vs, hs, ds, gs, ps),
+            (Some(hs), Some(ds), None) => ShaderSet::Tessellated(vs, hs, ds, ps),
+            (_, _, Some(gs)) => ShaderSet::Geometry(vs, gs, ps),
+            _ => ShaderSet::Simple(vs, ps),
+        })
+    }
+
+    /// Compiles, or takes from the cache, & links the shaders then creates the pipeline state,
+    /// returns it with the linked program's reflection info
+    pub(crate) fn create_pipeline<R, F, I>(
+        &self,
+        factory: &mut F,
+        cache: &ShaderCache,
+        primitive: Primitive,
+        rasterizer: state::Rasterizer,
+        init: I,
+    ) -> Result<(PipelineState<R, I::Meta>, ProgramInfo), ShaderWatchError>
+    where
+        R: Resources,
+        F: Factory<R>,
+        I: pso::PipelineInit,
+    {
+        let set = self.create_shader_set(factory, cache)?;
+        let program = factory
+            .create_program(&set)
+            .map_err(ShaderWatchError::Link)?;
+        let pso = factory
+            .create_pipeline_from_program(&program, primitive, rasterizer, init)
+            .map_err(|error| match error {
+                PipelineStateError::Program(e) => PipelineStateError::Program(e).into(),
+                PipelineStateError::DescriptorInit(error) => {
+                    let error = error.into();
+                    ShaderWatchError::PipelineMismatch {
+                        report: describe_mismatch(&error, Some(program.get_info())),
+                        error,
+                    }
+                }
+                PipelineStateError::DeviceCreate(_) => ShaderWatchError::PipelineCreate,
+            })?;
+        Ok((pso, program.get_info().clone()))
+    }
+}
+
+fn compile<S, C>(
+    stage: ShaderStage,
+    source: &ShaderSource,
+    create: C,
+) -> Result<S, ShaderWatchError>
+where
+    C: FnOnce(&[u8]) -> Result<S, CreateShaderError>,
+{
+    create(&source.code).map_err(|error| {
+        let diagnostics = match &error {
+            CreateShaderError::CompilationFailed(log) => parse_log(log, stage, source),
+            _ => vec![],
+        };
+        ShaderWatchError::Compile {
+            stage,
+            path: source.path().map(Path::to_path_buf),
+            error,
+            diagnostics,
+        }
+    })
+}
@@ -1,79 +1,591 @@
-use super::PsoCell;
-use gfx::{traits::FactoryExt, *};
+use super::{
+    cache::CacheLease,
+    config::PipelineConfig,
+    hub::{Subscription, WatcherBackend},
+    overrides::{apply_override, override_generation},
+    reload::dirty_generation,
+    source::{expand_includes, load_shader, normalize, ShaderSource},
+    stages::Stages,
+    Permutation, PermutationBuilder, PsoCell, ShaderCache, ShaderWatchError, ShaderWatchHub,
+};
+use gfx::*;
 use log::{debug, error, info};
-use notify::{self, Watcher};
 use std::{
-    error::Error,
-    fs::File,
-    io::prelude::*,
+    collections::{HashMap, VecDeque},
+    fs, mem,
     path::{Path, PathBuf},
-    sync::mpsc,
+    time::{Duration, Instant},
 };
 
-fn shader_bytes(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
-    let mut shader = Vec::new();
-    File::open(path)?.read_to_end(&mut shader)?;
-    Ok(shader)
+/// Time to wait for a changed file that is missing, ie removed by an editor's atomic save,
+/// to reappear before rebuilding anyway
+const MISSING_FILE_WAIT: Duration = Duration::from_secs(1);
+
+/// Shader file changes received but not yet rebuilt
+#[derive(Debug)]
+struct PendingChange {
+    paths: Vec<PathBuf>,
+    last_event: Instant,
+}
+
+/// Reads the pipeline config file, default settings if there is none
+#[cfg(feature = "config-file")]
+fn read_config(config: Option<&Path>) -> Result<PipelineConfig, ShaderWatchError> {
+    use super::config::load_config;
+    Ok(config.map(load_config).transpose()?.unwrap_or_default())
+}
+
+/// Config files are unsupported without the `config-file` feature, so never set
+#[cfg(not(feature = "config-file"))]
+fn read_config(_config: Option<&Path>) -> Result<PipelineConfig, ShaderWatchError> {
+    Ok(PipelineConfig::default())
+}
+
+/// Returns the primitive & rasterizer with the pipeline config & the global
+/// rasterizer override applied
+fn apply_settings(
+    config: &PipelineConfig,
+    primitive: Primitive,
+    rasterizer: state::Rasterizer,
+) -> (Primitive, state::Rasterizer) {
+    let (primitive, rasterizer) = config.apply(primitive, rasterizer);
+    (primitive, apply_override(rasterizer))
+}
+
+/// Loads each stage's shader source, with stages missing from `shaders` split from the single
+/// file `shader`, & injects the defines. Every file read, or attempted to be read, is added
+/// to `files`
+fn load_sources(
+    shaders: &Stages<PathBuf>,
+    shader: Option<&Path>,
+    defines: &[(String, String)],
+    files: &mut Vec<PathBuf>,
+) -> Result<Stages<ShaderSource>, ShaderWatchError> {
+    let mut sources = shaders.try_map(|path| load_shader(path, files))?;
+    if let Some(path) = shader {
+        sources = sources.or(load_shader(path, files)?.split_stages()?);
+    }
+    sources.for_each_mut(|source| source.define(defines));
+    Ok(sources)
+}
+
+/// Successfully built pipeline state & the shader files it was built from
+struct HistoryEntry<R: Resources, M> {
+    /// `None` for the active pipeline state, held by the cell
+    pso: Option<PipelineState<R, M>>,
+    hashes: Stages<u64>,
+    sources: Stages<ShaderSource>,
+    pipeline_config: PipelineConfig,
+    settings: (Primitive, state::Rasterizer),
+    /// Cell primitive & rasterizer the pipeline state was built with, before the config file
+    cell_settings: (Primitive, state::Rasterizer),
+    /// Cell `init_generation` the pipeline state was built with
+    init_generation: u64,
+    /// Rasterizer override generation the pipeline state was built with
+    override_generation: u64,
+    program: ProgramInfo,
+    /// Content of each shader file & include
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl<R: Resources, M> HistoryEntry<R, M> {
+    fn new(
+        sources: Stages<ShaderSource>,
+        pipeline_config: PipelineConfig,
+        settings: (Primitive, state::Rasterizer),
+        cell_settings: (Primitive, state::Rasterizer),
+        init_generation: u64,
+        override_generation: u64,
+        program: ProgramInfo,
+    ) -> HistoryEntry<R, M> {
+        let mut files: Vec<(PathBuf, Vec<u8>)> = Vec::new();
+        for (path, code) in sources.iter().flat_map(|source| &source.originals) {
+            if !files.iter().any(|(p, _)| p == path) {
+                files.push((path.clone(), code.clone()));
+            }
+        }
+        HistoryEntry {
+            pso: None,
+            hashes: sources.map(ShaderSource::code_hash),
+            sources,
+            pipeline_config,
+            settings,
+            cell_settings,
+            init_generation,
+            override_generation,
+            program,
+            files,
+        }
+    }
+
+    /// Returns the files whose content on disk differs from this entry's
+    fn changed_files(&self) -> impl Iterator<Item = &(PathBuf, Vec<u8>)> {
+        self.files
+            .iter()
+            .filter(|(path, code)| fs::read(path).ok().as_ref() != Some(code))
+    }
 }
 
 /// Container that watches shader files and reloads pipeline state object after modification
 pub struct WatcherPsoCell<R: Resources, F: Factory<R>, I: pso::PipelineInit> {
-    vertex_shader: PathBuf,
-    fragment_shader: PathBuf,
+    shaders: Stages<PathBuf>,
+    /// Single file shader providing stages missing from `shaders`
+    shader: Option<PathBuf>,
+    defines: Vec<(String, String)>,
     init: I,
+    /// Incremented by each `set_init`, as the init struct can't be compared
+    init_generation: u64,
     primitive: Primitive,
     rasterizer: state::Rasterizer,
-    _watcher: notify::RecommendedWatcher,
-    shader_mods: mpsc::Receiver<notify::Result<notify::Event>>,
+    /// Pipeline config file overriding `primitive` & `rasterizer`
+    config: Option<PathBuf>,
+    /// Shader sources of the active pipeline state, relinked when only settings change
+    sources: Stages<ShaderSource>,
+    /// Pipeline config file settings of the active pipeline state
+    pipeline_config: PipelineConfig,
+    /// Primitive & rasterizer of the active pipeline state
+    settings: (Primitive, state::Rasterizer),
+    /// Reflection info of the active pipeline state's program
+    program: ProgramInfo,
+    cache: ShaderCache,
+    /// Keeps the compiled shaders of the latest build cached, so a change to one stage
+    /// only recompiles that stage
+    lease: CacheLease,
+    /// `override_generation()` the pipeline state was last built with
+    override_generation: u64,
+    /// `dirty_generation()` last seen, a change marks the cell dirty
+    dirty_generation: u64,
+    watcher: Subscription,
+    /// Shader files & all files they include
+    dependencies: Vec<PathBuf>,
+    /// Source hash of each stage of the active pipeline state, `None` if built from fallback shaders
+    hashes: Option<Stages<u64>>,
+    last_error: Option<ShaderWatchError>,
+    /// Latest reload attempt failed
+    reload_failed: bool,
+    /// `None` until the shader files build, ie while using fallback shaders
+    last_build: Option<Instant>,
+    debounce: Duration,
+    pending: Option<PendingChange>,
+    /// Rebuild on the next `pso()` call regardless of file changes
+    dirty: bool,
+    /// Active pipeline state was activated from the history & differs from the shader files
+    rolled_back: bool,
+    paused: bool,
+    /// Recently built pipeline states, oldest first
+    history: VecDeque<HistoryEntry<R, I::Meta>>,
+    max_history: usize,
+    /// Index of the active pipeline state in `history`
+    history_pos: usize,
 
     factory: F,
     pso: PipelineState<R, I::Meta>,
+    generation: u64,
 }
 
 impl<R: Resources, F: Factory<R>, I: pso::PipelineInit + Clone> WatcherPsoCell<R, F, I> {
-    fn recv_modified_pso(&mut self) -> Option<PipelineState<R, I::Meta>>
+    /// Rebuilds the pipeline state if shader files have changed, returns `true` if replaced
+    fn recv_modified_pso(&mut self) -> bool
     where
         R: Resources,
         F: Factory<R>,
     {
-        let mut path_changed = None;
-        for notify::Event { paths, kind, .. } in self.shader_mods.try_iter().flatten() {
-            if path_changed.is_none() && (kind.is_modify() || kind.is_create()) {
-                path_changed = paths
-                    .into_iter()
-                    .find(|p| p == &self.vertex_shader || p == &self.fragment_shader)
+        let now = Instant::now();
+        let (changed, errors) = self.watcher.recv_changes();
+        for err in errors {
+            error!("Watcher error: {err}");
+            self.last_error = Some(err.into());
+        }
+        let dirty_generation = dirty_generation();
+        if self.dirty_generation != dirty_generation {
+            self.dirty_generation = dirty_generation;
+            self.dirty = true;
+        }
+        for path in changed {
+            let pending = self.pending.get_or_insert_with(|| PendingChange {
+                paths: vec![],
+                last_event: now,
+            });
+            pending.last_event = now;
+            if !pending.paths.contains(&path) {
+                pending.paths.push(path);
             }
         }
-        if let Some(changed) = path_changed {
-            match self.build_pso() {
-                Ok(pso) => {
-                    info!("{changed:?} changed");
-                    return Some(pso);
+
+        if self.paused {
+            return false;
+        }
+
+        let override_generation = override_generation();
+        let override_changed = self.override_generation != override_generation;
+        self.override_generation = override_generation;
+
+        if self.dirty {
+            self.dirty = false;
+            self.pending = None;
+            let rebuilt = self.rebuild(true);
+            if rebuilt {
+                info!("Shaders reloaded");
+            }
+            return rebuilt;
+        }
+
+        if override_changed {
+            // only the rasterizer changed, relink the active shaders rather than reading the files
+            match self.relink(self.primitive, self.rasterizer, None) {
+                Ok(()) => {
+                    info!("Rasterizer override applied");
+                    return true;
                 }
-                Err(err) => error!("{err:?}"),
-            };
+                Err(err) => {
+                    error!("{err}");
+                    self.last_error = Some(err);
+                }
+            }
+        }
+
+        // wait for files to be quiet before rebuilding, coalescing bursts of changes
+        match &self.pending {
+            Some(pending) if now.duration_since(pending.last_event) >= self.debounce => {
+                // editors saving atomically may remove a file before replacing it
+                if now.duration_since(pending.last_event) < MISSING_FILE_WAIT
+                    && pending.paths.iter().any(|p| !p.exists())
+                {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+        let Some(PendingChange { paths: changed, .. }) = self.pending.take() else {
+            return false;
+        };
+        let rebuilt = self.rebuild(false);
+        if rebuilt {
+            info!("{changed:?} changed");
+        } else if !self.reload_failed {
+            debug!("{changed:?} changed, ignoring as shader content is unchanged");
         }
-        None
+        rebuilt
     }
 
-    fn build_pso(&mut self) -> Result<PipelineState<R, I::Meta>, Box<dyn Error>>
-    where
-        R: Resources,
-        F: Factory<R>,
-    {
-        let fragment_shader = shader_bytes(&self.fragment_shader)?;
+    /// Rebuilds the pipeline state from the shader files recording the outcome,
+    /// returns `false` if the build failed or was skipped as the content is unchanged
+    fn rebuild(&mut self, force: bool) -> bool {
+        match self.build_pso(force) {
+            Ok(rebuilt) => {
+                self.last_error = None;
+                self.reload_failed = false;
+                self.rolled_back = false;
+                if rebuilt {
+                    self.last_build = Some(Instant::now());
+                }
+                rebuilt
+            }
+            Err(err) => {
+                error!("{err}");
+                self.last_error = Some(err);
+                self.reload_failed = true;
+                false
+            }
+        }
+    }
+
+    /// Rebuilds the pipeline state from the shader files immediately, regardless of
+    /// file changes. On failure the previous pipeline state is kept & the error returned,
+    /// also available from `last_error()`
+    pub fn reload(&mut self) -> Result<(), ShaderWatchError> {
+        self.pending = None;
+        self.dirty = false;
+        if self.rebuild(true) {
+            info!("Shaders reloaded");
+        }
+        match &self.last_error {
+            Some(err) if self.reload_failed => Err(err.clone()),
+            _ => Ok(()),
+        }
+    }
 
-        let vertex_shader = shader_bytes(&self.vertex_shader)?;
+    /// Relinks the active pipeline state's shaders with a new primitive, without reading the
+    /// shader files. On failure the previous pipeline state & primitive are kept.
+    /// A `config_file` primitive takes precedence
+    pub fn set_primitive(&mut self, primitive: Primitive) -> Result<(), ShaderWatchError> {
+        self.relink(primitive, self.rasterizer, None)
+    }
+
+    /// Relinks the active pipeline state's shaders with a new rasterizer, without reading the
+    /// shader files. On failure the previous pipeline state & rasterizer are kept.
+    /// `config_file` rasterizer settings take precedence
+    pub fn set_rasterizer(
+        &mut self,
+        rasterizer: state::Rasterizer,
+    ) -> Result<(), ShaderWatchError> {
+        self.relink(self.primitive, rasterizer, None)
+    }
 
-        let set = self
-            .factory
-            .create_shader_set(&vertex_shader, &fragment_shader)?;
-        Ok(self.factory.create_pipeline_state(
-            &set,
-            self.primitive,
-            self.rasterizer,
+    /// Relinks the active pipeline state's shaders with a new pipeline init struct, ie with
+    /// different blend or depth state, without reading the shader files. On failure the
+    /// previous pipeline state & init struct are kept
+    pub fn set_init(&mut self, init: I) -> Result<(), ShaderWatchError> {
+        self.relink(self.primitive, self.rasterizer, Some(init))
+    }
+
+    /// Rebuilds the pipeline state from the active shader sources, compiled shaders are
+    /// taken from the cache. `init` replaces the init struct if `Some`. On failure the
+    /// previous pipeline state & settings are kept
+    fn relink(
+        &mut self,
+        primitive: Primitive,
+        rasterizer: state::Rasterizer,
+        init: Option<I>,
+    ) -> Result<(), ShaderWatchError> {
+        let override_generation = override_generation();
+        let settings = apply_settings(&self.pipeline_config, primitive, rasterizer);
+        let (pso, program) = self.sources.create_pipeline(
+            &mut self.factory,
+            &self.cache,
+            settings.0,
+            settings.1,
+            init.clone().unwrap_or_else(|| self.init.clone()),
+        )?;
+        self.lease = self.cache.lease::<R>(&self.sources);
+        self.pso = pso;
+        self.primitive = primitive;
+        self.rasterizer = rasterizer;
+        if let Some(init) = init {
+            self.init = init;
+            self.init_generation += 1;
+        }
+        self.settings = settings;
+        self.program = program;
+        self.override_generation = override_generation;
+        self.generation += 1;
+        if let Some(active) = self.history.get_mut(self.history_pos) {
+            active.settings = settings;
+            active.cell_settings = (primitive, rasterizer);
+            active.init_generation = self.init_generation;
+            active.override_generation = override_generation;
+            active.program = self.program.clone();
+        }
+        Ok(())
+    }
+
+    /// Stops rebuilding the pipeline state on file changes, changes are buffered until
+    /// `resume_watching()`. An explicit `reload()` still rebuilds
+    pub fn pause_watching(&mut self) {
+        if !self.paused {
+            debug!("Watching paused");
+            self.paused = true;
+        }
+    }
+
+    /// Resumes rebuilding on file changes, if any were buffered while paused the pipeline state
+    /// is rebuilt once on the next `pso()` call
+    pub fn resume_watching(&mut self) {
+        if self.paused {
+            debug!("Watching resumed");
+            self.paused = false;
+        }
+    }
+
+    /// Returns `true` while watching is paused
+    pub fn is_watching_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Marks the pipeline state to be rebuilt from the shader files on the next `pso()` call,
+    /// regardless of file changes. See `mark_all_dirty` to mark every cell
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Returns the latest reload or watcher error, cleared by a successful reload.
+    /// Reflects file changes received by the last call to `pso()`
+    pub fn last_error(&self) -> Option<&ShaderWatchError> {
+        self.last_error.as_ref()
+    }
+
+    /// Returns the reflected interface of the active pipeline state's shader program,
+    /// ie its vertex attributes, constant buffers, textures & outputs
+    pub fn program_info(&self) -> &ProgramInfo {
+        &self.program
+    }
+
+    /// Returns when the shader files were last successfully built,
+    /// `None` if they haven't been, ie the initial build failed & fallback shaders are used
+    pub fn last_build(&self) -> Option<Instant> {
+        self.last_build
+    }
+
+    /// Returns `true` when shader files have changed but the active pipeline state has not been
+    /// rebuilt from them, ie a reload failed, is waiting for the debounce time, the cell
+    /// has been marked dirty or a rolled back pipeline state differs from the files
+    pub fn is_stale(&self) -> bool {
+        self.reload_failed || self.pending.is_some() || self.dirty || self.rolled_back
+    }
+
+    /// Rebuilds the pipeline state from the shader files, returns `false` if their content
+    /// is the same as the active pipeline state's, unless forced
+    fn build_pso(&mut self, force: bool) -> Result<bool, ShaderWatchError> {
+        let sources = self.load_shaders()?;
+        let pipeline_config = read_config(self.config.as_deref())?;
+        let settings = apply_settings(&pipeline_config, self.primitive, self.rasterizer);
+        let hashes = sources.map(ShaderSource::code_hash);
+        if !force && self.hashes.as_ref() == Some(&hashes) && self.settings == settings {
+            return Ok(false);
+        }
+
+        let (primitive, rasterizer) = settings;
+        let (pso, program) = sources.create_pipeline(
+            &mut self.factory,
+            &self.cache,
+            primitive,
+            rasterizer,
             self.init.clone(),
-        )?)
+        )?;
+        self.lease = self.cache.lease::<R>(&sources);
+        self.hashes = Some(hashes);
+        self.sources = sources;
+        self.pipeline_config = pipeline_config;
+        self.settings = settings;
+        self.program = program;
+        let previous = mem::replace(&mut self.pso, pso);
+        self.generation += 1;
+        if let Some(active) = self.history.get_mut(self.history_pos) {
+            active.pso = Some(previous);
+        }
+        self.remember();
+        Ok(true)
+    }
+
+    /// Adds the active pipeline state to the history discarding any rolled back from
+    fn remember(&mut self) {
+        if self.max_history == 0 {
+            return;
+        }
+        self.history.truncate(self.history_pos + 1);
+        self.history.push_back(HistoryEntry::new(
+            self.sources.clone(),
+            self.pipeline_config.clone(),
+            self.settings,
+            (self.primitive, self.rasterizer),
+            self.init_generation,
+            override_generation(),
+            self.program.clone(),
+        ));
+        while self.history.len() > self.max_history {
+            self.history.pop_front();
+        }
+        self.history_pos = self.history.len() - 1;
+    }
+
+    /// Makes the pipeline state at `pos` in the history active,
+    /// returns `false` if it has no pipeline state to swap in
+    fn activate(&mut self, pos: usize) -> bool {
+        let Some(pso) = self.history[pos].pso.take() else {
+            return false;
+        };
+        let previous = mem::replace(&mut self.pso, pso);
+        self.history[self.history_pos].pso = Some(previous);
+        self.hashes = Some(self.history[pos].hashes.clone());
+        self.sources = self.history[pos].sources.clone();
+        self.lease = self.cache.lease::<R>(&self.sources);
+        self.pipeline_config = self.history[pos].pipeline_config.clone();
+        self.settings = self.history[pos].settings;
+        self.program = self.history[pos].program.clone();
+        self.history_pos = pos;
+        self.generation += 1;
+        // further changes to the files replace the rolled back pipeline state
+        self.reload_failed = false;
+        self.pending = None;
+        self.dirty = false;
+        self.rolled_back = self.history[pos].changed_files().next().is_some();
+
+        // settings changed since the pipeline state was built, ie by `set_init`
+        // or `set_rasterizer_override`
+        let entry = &self.history[pos];
+        if entry.cell_settings != (self.primitive, self.rasterizer)
+            || entry.init_generation != self.init_generation
+            || entry.override_generation != override_generation()
+        {
+            debug!("Relinking with the current settings");
+            if let Err(err) = self.relink(self.primitive, self.rasterizer, None) {
+                error!("{err}");
+                self.last_error = Some(err);
+            }
+        }
+        true
+    }
+
+    /// Activates the previously built pipeline state from the history, without changing
+    /// the shader files. Returns `false` if there is no older pipeline state. One built before
+    /// a `set_primitive`, `set_rasterizer`, `set_init` or `set_rasterizer_override` is relinked
+    /// with the current settings.
+    ///
+    /// The next shader file change builds from the files as usual, until then `is_stale()`
+    /// is `true` if the files differ from the rolled back pipeline state
+    pub fn rollback(&mut self) -> bool {
+        if self.history_pos == 0 || self.history.is_empty() {
+            return false;
+        }
+        if !self.activate(self.history_pos - 1) {
+            return false;
+        }
+        info!("Rolled back to previous pipeline state");
+        true
+    }
+
+    /// Reverses a `rollback()`, returns `false` if the active pipeline state is the newest
+    pub fn roll_forward(&mut self) -> bool {
+        if self.history_pos + 1 >= self.history.len() {
+            return false;
+        }
+        if !self.activate(self.history_pos + 1) {
+            return false;
+        }
+        info!("Rolled forward to next pipeline state");
+        true
+    }
+
+    /// Returns the number of pipeline states in the history, including the active one
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Writes the shader files & includes the active pipeline state was built from,
+    /// ie after a `rollback()`, leaving unchanged files untouched
+    pub fn write_sources(&self) -> Result<(), ShaderWatchError> {
+        let Some(entry) = self.history.get(self.history_pos) else {
+            return Ok(());
+        };
+        for (path, code) in entry.changed_files() {
+            info!("Writing {path:?}");
+            fs::write(path, code).map_err(|error| ShaderWatchError::Io {
+                path: path.clone(),
+                error,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Loads each stage's shader source & watches all files read
+    fn load_shaders(&mut self) -> Result<Stages<ShaderSource>, ShaderWatchError> {
+        let mut dependencies = Vec::new();
+        let sources = load_sources(
+            &self.shaders,
+            self.shader.as_deref(),
+            &self.defines,
+            &mut dependencies,
+        );
+
+        // includes may have changed, keep watching the previous files too
+        // as a failed load may not have reached all of them
+        for dep in dependencies {
+            if !self.dependencies.contains(&dep) {
+                self.dependencies.push(dep);
+            }
+        }
+        self.watcher.watch(&self.dependencies)?;
+        sources
     }
 }
 
@@ -81,10 +593,16 @@ impl<R: Resources, F: Factory<R>, I: pso::PipelineInit + Clone> PsoCell<R, F, I>
     for WatcherPsoCell<R, F, I>
 {
     fn pso(&mut self) -> &mut PipelineState<R, I::Meta> {
-        if let Some(updated) = self.recv_modified_pso() {
-            self.pso = updated;
-        }
-        &mut self.pso
+        self.pso_with_status().0
+    }
+
+    fn pso_with_status(&mut self) -> (&mut PipelineState<R, I::Meta>, bool) {
+        let updated = self.recv_modified_pso();
+        (&mut self.pso, updated)
+    }
+
+    fn generation(&self) -> u64 {
+        self.generation
     }
 
     fn factory(&mut self) -> &mut F {
@@ -93,10 +611,18 @@ impl<R: Resources, F: Factory<R>, I: pso::PipelineInit + Clone> PsoCell<R, F, I>
 }
 
 /// Builds `WatcherPsoCell`
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct WatcherPsoCellBuilder<I: pso::PipelineInit> {
-    vertex_shader: Option<PathBuf>,
-    fragment_shader: Option<PathBuf>,
+    shaders: Stages<PathBuf>,
+    shader: Option<PathBuf>,
+    defines: Vec<(String, String)>,
+    fallback: Option<Stages<Vec<u8>>>,
+    debounce: Duration,
+    backend: WatcherBackend,
+    hub: Option<ShaderWatchHub>,
+    cache: Option<ShaderCache>,
+    max_history: usize,
+    config: Option<PathBuf>,
     primitive: Primitive,
     rasterizer: state::Rasterizer,
     init: I,
@@ -105,8 +631,16 @@ pub struct WatcherPsoCellBuilder<I: pso::PipelineInit> {
 impl<I: pso::PipelineInit + Clone> WatcherPsoCellBuilder<I> {
     pub fn using(init_struct: I) -> WatcherPsoCellBuilder<I> {
         WatcherPsoCellBuilder {
-            vertex_shader: None,
-            fragment_shader: None,
+            shaders: Stages::default(),
+            shader: None,
+            defines: Vec::new(),
+            fallback: None,
+            debounce: Duration::ZERO,
+            backend: WatcherBackend::default(),
+            hub: None,
+            cache: None,
+            max_history: 0,
+            config: None,
             init: init_struct,
             primitive: Primitive::TriangleList,
             rasterizer: state::Rasterizer::new_fill(),
@@ -114,12 +648,124 @@ impl<I: pso::PipelineInit + Clone> WatcherPsoCellBuilder<I> {
     }
 
     pub fn vertex_shader<P: Into<PathBuf>>(mut self, path: P) -> WatcherPsoCellBuilder<I> {
-        self.vertex_shader = Some(path.into());
+        self.shaders.vertex = Some(path.into());
+        self
+    }
+
+    /// Optional tessellation control shader stage, requires a domain shader
+    pub fn hull_shader<P: Into<PathBuf>>(mut self, path: P) -> WatcherPsoCellBuilder<I> {
+        self.shaders.hull = Some(path.into());
+        self
+    }
+
+    /// Optional tessellation evaluation shader stage, requires a hull shader
+    pub fn domain_shader<P: Into<PathBuf>>(mut self, path: P) -> WatcherPsoCellBuilder<I> {
+        self.shaders.domain = Some(path.into());
+        self
+    }
+
+    /// Optional geometry shader stage
+    pub fn geometry_shader<P: Into<PathBuf>>(mut self, path: P) -> WatcherPsoCellBuilder<I> {
+        self.shaders.geometry = Some(path.into());
         self
     }
 
     pub fn fragment_shader<P: Into<PathBuf>>(mut self, path: P) -> WatcherPsoCellBuilder<I> {
-        self.fragment_shader = Some(path.into());
+        self.shaders.fragment = Some(path.into());
+        self
+    }
+
+    /// Single file shader with each stage's code following a `#pragma stage <name>` line,
+    /// ie `#pragma stage vertex` & `#pragma stage fragment`. Code above the first marker is
+    /// shared by every stage. Stages set individually take precedence over the file's
+    pub fn shader<P: Into<PathBuf>>(mut self, path: P) -> WatcherPsoCellBuilder<I> {
+        self.shader = Some(path.into());
+        self
+    }
+
+    /// Defines a preprocessor macro in every shader stage, injected as `#define name value`
+    /// after the `#version` directive so the same shader files can back cells with different
+    /// features. An empty value defines the name alone, ie `"SHADOWS", ""`
+    pub fn define<N: Into<String>, V: Into<String>>(
+        mut self,
+        name: N,
+        value: V,
+    ) -> WatcherPsoCellBuilder<I> {
+        let name = name.into();
+        self.defines.retain(|(n, _)| *n != name);
+        self.defines.push((name, value.into()));
+        self
+    }
+
+    /// Shader source used to build the initial pipeline state if the watched shaders fail to,
+    /// ie a plain error colour shader. The watched shaders replace it once fixed.
+    pub fn fallback_shaders(
+        mut self,
+        vertex_shader: &[u8],
+        fragment_shader: &[u8],
+    ) -> WatcherPsoCellBuilder<I> {
+        self.fallback = Some(Stages {
+            vertex: Some(vertex_shader.into()),
+            fragment: Some(fragment_shader.into()),
+            ..Stages::default()
+        });
+        self
+    }
+
+    /// Time shader files must be unchanged before rebuilding, avoids building partially
+    /// written files. Changes to any stage within the window are coalesced into a single
+    /// rebuild. Default zero, rebuilds on the first `pso()` call after a change
+    pub fn debounce(mut self, debounce: Duration) -> WatcherPsoCellBuilder<I> {
+        self.debounce = debounce;
+        self
+    }
+
+    /// File watching implementation, default `WatcherBackend::Recommended`.
+    /// Ignored when using a `hub`
+    pub fn watcher_backend(mut self, backend: WatcherBackend) -> WatcherPsoCellBuilder<I> {
+        self.backend = backend;
+        self
+    }
+
+    /// Number of successfully built pipeline states to keep for `WatcherPsoCell::rollback`,
+    /// including the active one. Default zero, no history is kept
+    pub fn history(mut self, len: usize) -> WatcherPsoCellBuilder<I> {
+        self.max_history = len;
+        self
+    }
+
+    /// Watches shader files with a hub shared with other cells, rather than a file watcher
+    /// of this cell's own
+    pub fn hub(mut self, hub: &ShaderWatchHub) -> WatcherPsoCellBuilder<I> {
+        self.hub = Some(hub.clone());
+        self
+    }
+
+    /// Compiles shaders with a cache shared with other cells, so cells using the same shader
+    /// file compile it once. Each cell otherwise has a cache of its own, either way a change
+    /// to one stage only recompiles that stage
+    pub fn shader_cache(mut self, cache: &ShaderCache) -> WatcherPsoCellBuilder<I> {
+        self.cache = Some(cache.clone());
+        self
+    }
+
+    /// Watched TOML file of pipeline settings applied over `primitive` & `rasterizer`,
+    /// editing it rebuilds the pipeline state. Unset keys keep the builder's setting.
+    /// Requires the default `config-file` feature
+    ///
+    /// ```toml
+    /// primitive = "triangle_list" # point_list, line_strip, patch_list, ...
+    /// patch_size = 3              # control points for patch_list
+    /// front_face = "counter_clockwise"
+    /// cull_face = "back"          # nothing, front
+    /// polygon_mode = "line"       # fill, point
+    /// line_width = 2
+    /// offset = [1, 1]             # polygon offset slope & units
+    /// multisample = true
+    /// ```
+    #[cfg(feature = "config-file")]
+    pub fn config_file<P: Into<PathBuf>>(mut self, path: P) -> WatcherPsoCellBuilder<I> {
+        self.config = Some(path.into());
         self
     }
 
@@ -133,50 +779,137 @@ impl<I: pso::PipelineInit + Clone> WatcherPsoCellBuilder<I> {
         self
     }
 
-    pub fn build<R, F>(self, mut factory: F) -> Result<WatcherPsoCell<R, F, I>, Box<dyn Error>>
+    pub fn build<R, F>(self, mut factory: F) -> Result<WatcherPsoCell<R, F, I>, ShaderWatchError>
     where
         R: Resources,
         F: Factory<R>,
     {
-        let (tx, shader_mods) = mpsc::channel();
-        let mut watcher = notify::RecommendedWatcher::new(tx, <_>::default())?;
-        let pso = {
-            let vs = self.vertex_shader.as_ref().ok_or("missing vertex shader")?;
-            let fs = self
-                .fragment_shader
-                .as_ref()
-                .ok_or("missing fragment shader")?;
-            let vs_dir = vs.parent().unwrap_or(vs);
-            let fs_dir = fs.parent().unwrap_or(fs);
-
-            debug!("Watching {:?}", &[vs, fs]);
-            watcher.watch(vs_dir, notify::RecursiveMode::NonRecursive)?;
-            if fs_dir != vs_dir {
-                watcher.watch(fs_dir, notify::RecursiveMode::NonRecursive)?;
-            }
+        if self.shader.is_none() {
+            self.shaders.check()?;
+        }
 
-            let fragment_shader = shader_bytes(fs)?;
-            let vertex_shader = shader_bytes(vs)?;
-            let set = factory.create_shader_set(&vertex_shader, &fragment_shader)?;
-            factory.create_pipeline_state(
-                &set,
-                self.primitive,
-                self.rasterizer,
+        let mut watcher = match &self.hub {
+            Some(hub) => hub.subscribe(),
+            None => ShaderWatchHub::with_backend(self.backend)?.subscribe(),
+        };
+        let cache = self.cache.clone().unwrap_or_default();
+        let override_generation = override_generation();
+        let config = self.config.as_deref().map(normalize);
+        let mut dependencies: Vec<_> = config.iter().cloned().collect();
+        let sources = load_sources(
+            &self.shaders,
+            self.shader.as_deref(),
+            &self.defines,
+            &mut dependencies,
+        );
+        watcher.watch(&dependencies)?;
+        let pso = sources.and_then(|sources| {
+            let pipeline_config = read_config(config.as_deref())?;
+            let (primitive, rasterizer) =
+                apply_settings(&pipeline_config, self.primitive, self.rasterizer);
+            let (pso, program) = sources.create_pipeline(
+                &mut factory,
+                &cache,
+                primitive,
+                rasterizer,
                 self.init.clone(),
-            )?
+            )?;
+            Ok((pso, program, sources, pipeline_config))
+        });
+
+        let mut last_error = None;
+        let (pso, program, sources, pipeline_config) = match (pso, &self.fallback) {
+            (Ok(built), _) => built,
+            (Err(err), Some(fallback)) => {
+                error!("{err}");
+                info!("Using fallback shaders");
+                last_error = Some(err);
+                let sources = fallback.try_map(|bytes| expand_includes(bytes, &HashMap::new()))?;
+                let (primitive, rasterizer) =
+                    apply_settings(&PipelineConfig::default(), self.primitive, self.rasterizer);
+                let (pso, program) = sources.create_pipeline(
+                    &mut factory,
+                    &cache,
+                    primitive,
+                    rasterizer,
+                    self.init.clone(),
+                )?;
+                (pso, program, sources, PipelineConfig::default())
+            }
+            (Err(err), None) => return Err(err),
         };
+        let lease = cache.lease::<R>(&sources);
+        let settings = apply_settings(&pipeline_config, self.primitive, self.rasterizer);
 
-        Ok(WatcherPsoCell {
-            vertex_shader: self.vertex_shader.ok_or("missing vertex shader")?,
-            fragment_shader: self.fragment_shader.ok_or("missing fragment shader")?,
+        let mut cell = WatcherPsoCell {
+            shaders: self.shaders,
+            shader: self.shader,
+            defines: self.defines,
             init: self.init,
+            init_generation: 0,
             primitive: self.primitive,
             rasterizer: self.rasterizer,
-            _watcher: watcher,
-            shader_mods,
+            config,
+            sources,
+            pipeline_config,
+            settings,
+            program,
+            cache,
+            lease,
+            override_generation,
+            dirty_generation: dirty_generation(),
+            watcher,
+            dependencies,
+            hashes: None,
+            reload_failed: last_error.is_some(),
+            last_error,
+            last_build: None,
+            debounce: self.debounce,
+            pending: None,
+            dirty: false,
+            rolled_back: false,
+            paused: false,
+            history: VecDeque::new(),
+            max_history: self.max_history,
+            history_pos: 0,
 
             factory,
             pso,
-        })
+            generation: 0,
+        };
+        if cell.last_error.is_none() {
+            cell.last_build = Some(Instant::now());
+            cell.hashes = Some(cell.sources.map(ShaderSource::code_hash));
+            cell.remember();
+        }
+        Ok(cell)
+    }
+}
+
+impl<R, F, I> PermutationBuilder<R, F> for WatcherPsoCellBuilder<I>
+where
+    R: Resources,
+    F: Factory<R>,
+    I: pso::PipelineInit + Clone,
+{
+    type Init = I;
+    type Cell = WatcherPsoCell<R, F, I>;
+
+    fn share(mut self) -> Result<WatcherPsoCellBuilder<I>, ShaderWatchError> {
+        if self.hub.is_none() {
+            self.hub = Some(ShaderWatchHub::with_backend(self.backend)?);
+        }
+        self.cache.get_or_insert_with(ShaderCache::new);
+        Ok(self)
+    }
+
+    fn permutation(self, permutation: &Permutation) -> WatcherPsoCellBuilder<I> {
+        permutation
+            .defines()
+            .fold(self, |builder, (name, value)| builder.define(name, value))
+    }
+
+    fn build_cell(self, factory: F) -> Result<WatcherPsoCell<R, F, I>, ShaderWatchError> {
+        self.build(factory)
     }
 }